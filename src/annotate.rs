@@ -1,9 +1,407 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::BufReader;
 use std::io::{self, BufRead, Write};
-use std::process::{Command, Stdio};
-use std::sync::mpsc;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread::ScopedJoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Old side line ranges of the hunks of a file, as start and exclusive end.
+type FileHunks = (String, Vec<(u32, u32)>);
+
+/// Runs a git command to completion, such as `Command::output`.
+type Runner = Arc<dyn Fn(&mut Command) -> io::Result<Output> + Send + Sync>;
+
+/// Property of blamed commits to derive the annotation color from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorBy {
+    /// A stable color derived from the commit-id.
+    Commit,
+    /// A stable color derived from the commit author.
+    Author,
+    /// A heatmap color from the commit age, recent commits are red.
+    Age,
+}
+
+/// Column of the annotation prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Column {
+    /// The abbreviated commit-id.
+    Hash,
+    /// The author name of the commit.
+    Author,
+    /// The initials of the author name, such as JD for John Doe.
+    Initials,
+    /// The author date of the commit, in UTC.
+    Date,
+    /// The author date of the commit relative to now, such as 3 weeks ago.
+    RelativeDate,
+    /// The subject of the commit, truncated to a fixed width.
+    Subject,
+    /// The age of the commit in years, months, weeks or days, such as 3M.
+    Age,
+    /// The number of commits since the commit, up to the blamed revision.
+    Distance,
+}
+
+/// Order of the printed candidate commits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CandidateSort {
+    /// Oldest author date first.
+    #[default]
+    Date,
+    /// Oldest first along the first-parent history of the blamed commit, then by date.
+    TopoFirstParent,
+    /// Most annotated lines first, then by date.
+    Frequency,
+}
+
+/// Format of the date shown with printed candidate commits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CandidateDate {
+    /// The author date and time in UTC, such as 2023-11-14 22:13:20 +0000.
+    Iso,
+    /// The author date in UTC, such as 2023-11-14.
+    Short,
+    /// The author date relative to now, such as 3 weeks ago.
+    Relative,
+}
+
+/// Annotation of context lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ContextColumn {
+    /// Show the commit on every context line.
+    #[default]
+    Always,
+    /// Leave the column of context lines blank, showing commits on changed lines only.
+    Changed,
+    /// Omit the column for context lines.
+    Never,
+}
+
+/// Side of the content to put the annotation column on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Side {
+    /// Before the content.
+    #[default]
+    Left,
+    /// After the content, padded to a width.
+    Right,
+}
+
+/// Content that blame operates on, which the old side line numbers of the diff refer to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Source {
+    /// The file in the commit being blamed, `HEAD` by default.
+    #[default]
+    Head,
+    /// The staged file, as with `--cached`.
+    Index,
+    /// The file in the work tree.
+    Worktree,
+}
+
+/// Named color palette for annotations.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Theme {
+    /// Bright colors for dark terminal backgrounds.
+    #[default]
+    Dark,
+    /// Dark colors for light terminal backgrounds.
+    Light,
+    /// The accent colors of the Solarized palette.
+    Solarized,
+}
+
+impl Theme {
+    fn hash_colors(&self) -> &'static [&'static str] {
+        match self {
+            Theme::Dark => &[
+                "31", "32", "33", "34", "35", "36", "91", "92", "93", "94", "95", "96",
+            ],
+            Theme::Light => &[
+                "38;5;88", "38;5;22", "38;5;94", "38;5;18", "38;5;90", "38;5;23", "38;5;124",
+                "38;5;28", "38;5;130", "38;5;19", "38;5;127", "38;5;30",
+            ],
+            Theme::Solarized => &[
+                "38;5;136", "38;5;166", "38;5;160", "38;5;125", "38;5;61", "38;5;33", "38;5;37",
+                "38;5;64",
+            ],
+        }
+    }
+
+    fn age_colors(&self) -> &'static [&'static str; 6] {
+        match self {
+            Theme::Dark => &[
+                "38;5;196", "38;5;208", "38;5;220", "38;5;34", "38;5;37", "38;5;27",
+            ],
+            Theme::Light => &[
+                "38;5;160", "38;5;166", "38;5;136", "38;5;28", "38;5;30", "38;5;25",
+            ],
+            Theme::Solarized => &[
+                "38;5;160", "38;5;166", "38;5;136", "38;5;64", "38;5;37", "38;5;33",
+            ],
+        }
+    }
+
+    fn hash_color(&self, key: &str) -> &'static str {
+        let palette = self.hash_colors();
+        // FNV-1a, as the std hasher is not stable across runs
+        let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+        });
+        palette[(hash % palette.len() as u64) as usize]
+    }
+
+    fn age_color(&self, age: u64) -> &'static str {
+        const DAY: u64 = 24 * 60 * 60;
+        let palette = self.age_colors();
+        match age / DAY {
+            0..=6 => palette[0],
+            7..=30 => palette[1],
+            31..=90 => palette[2],
+            91..=365 => palette[3],
+            366..=1095 => palette[4],
+            _ => palette[5],
+        }
+    }
+}
+
+/// Commit properties looked up for annotations.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct CommitInfo {
+    sha: String,
+    author: String,
+    time: u64,
+    subject: String,
+}
+
+/// Kind of a line in a unified diff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineKind {
+    /// Any line outside of hunk content, such as file and hunk headers.
+    Header,
+    /// An unchanged context line.
+    Context,
+    /// A line removed by the diff.
+    Removed,
+    /// A line added by the diff.
+    Added,
+}
+
+/// A diff line with its blame annotation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnotatedLine {
+    /// The line as read from the diff, without line terminator.
+    pub line: String,
+    /// The kind of the line.
+    pub kind: LineKind,
+    /// The commit-id as blamed for context and removed lines; boundary commits are prefixed
+    /// with `^`, uncommitted lines are all zeros.
+    pub commit: Option<String>,
+    /// The rendered annotation prefix, if the line gets one.
+    pub prefix: Option<String>,
+    /// Whether the line was terminated with CRLF rather than LF.
+    pub crlf: bool,
+    /// The old side path of the file the line belongs to, if known.
+    pub file: Option<String>,
+    /// The old side line number of context and removed lines.
+    pub old_line: Option<u32>,
+    /// Whether the line belongs to a symlink, its content being a link target, not source.
+    pub symlink: bool,
+}
+
+impl AnnotatedLine {
+    fn context_commit(&self) -> Option<String> {
+        self.commit
+            .clone()
+            .filter(|_| self.kind == LineKind::Context)
+    }
+
+    /// Whether the line was blamed to a boundary commit, beyond the blamed history.
+    pub fn is_boundary(&self) -> bool {
+        self.commit
+            .as_ref()
+            .is_some_and(|commit| commit.starts_with('^'))
+    }
+
+    /// Whether the line was blamed to uncommitted changes.
+    pub fn is_uncommitted(&self) -> bool {
+        self.commit
+            .as_ref()
+            .is_some_and(|commit| !commit.is_empty() && commit.chars().all(|c| c == '0'))
+    }
+}
+
+/// Fold runs of context lines sharing the same annotation.
+///
+/// Lines are buffered while they share the same gutter prefix. When the run ends and is longer
+/// than the fold limit, only its head and tail are written, with a summary line in between.
+/// Annotated lines longer than the wrap width are soft-wrapped to continuation rows.
+struct ContextFolder {
+    fold: Option<usize>,
+    wrap: Option<usize>,
+    right: Option<usize>,
+    pfx: String,
+    commit: Option<String>,
+    run: Vec<String>,
+}
+
+impl ContextFolder {
+    fn new(fold: Option<usize>, wrap: Option<usize>, right: Option<usize>) -> Self {
+        ContextFolder {
+            fold,
+            wrap,
+            right,
+            pfx: String::new(),
+            commit: None,
+            run: Vec::new(),
+        }
+    }
+
+    fn render(&self, pfx: &str, line: &str) -> String {
+        if let Some(width) = self.right {
+            // pad the content so the columns line up at the width, including the column
+            let (line, cr) = match line.strip_suffix('\r') {
+                Some(line) => (line, "\r"),
+                None => (line, ""),
+            };
+            let column = pfx.trim_end_matches(' ');
+            let indent = strip_ansi_escapes::strip_str(column).chars().count() + 1;
+            let pad = width.saturating_sub(indent + Self::display_width(line));
+            return format!("{line}{:pad$} {column}{cr}", "");
+        }
+        match self.wrap {
+            Some(width) => {
+                // keep a CR of CRLF terminated lines at the end of the last row
+                let (line, cr) = match line.strip_suffix('\r') {
+                    Some(line) => (line, "\r"),
+                    None => (line, ""),
+                };
+                let indent = strip_ansi_escapes::strip_str(pfx).chars().count();
+                let rows = Self::wrap(line, indent, width.saturating_sub(indent).max(1));
+                format!("{}{}{}", pfx, rows.join(&format!("\n{:indent$}", "")), cr)
+            }
+            None => format!("{}{}", pfx, line),
+        }
+    }
+
+    fn display_width(line: &str) -> usize {
+        // escape sequences take no space, tabs advance to the next terminal tab stop
+        let mut col = 0;
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\x1b' => {
+                    if chars.next() == Some('[') {
+                        chars.by_ref().find(|c| ('@'..='~').contains(c));
+                    }
+                }
+                '\t' => col += 8 - col % 8,
+                _ => col += 1,
+            }
+        }
+        col
+    }
+
+    fn wrap(line: &str, indent: usize, width: usize) -> Vec<String> {
+        // escape sequences take no space, tabs advance to the next terminal tab stop
+        let mut rows = vec![String::new()];
+        let mut col = 0;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            let row = rows.last_mut().unwrap();
+            if c == '\x1b' {
+                row.push(c);
+                if chars.next_if_eq(&'[').is_some() {
+                    row.push('[');
+                    for c in chars.by_ref() {
+                        row.push(c);
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                continue;
+            }
+            let tab = |col: usize| 8 - (indent + col) % 8;
+            let mut advance = if c == '\t' { tab(col) } else { 1 };
+            if col > 0 && col + advance > width {
+                rows.push(String::new());
+                col = 0;
+                advance = if c == '\t' { tab(col) } else { 1 };
+            }
+            rows.last_mut().unwrap().push(c);
+            col += advance;
+        }
+        rows
+    }
+
+    fn write<W: Write>(
+        &mut self,
+        writer: &mut W,
+        pfx: Option<String>,
+        context: Option<String>,
+        line: &str,
+    ) -> io::Result<()> {
+        match pfx {
+            // runs are of the blamed commit, blank or per-line columns may not tell them apart
+            Some(pfx) if context.is_some() && self.fold.is_some() => {
+                if context != self.commit {
+                    self.flush(writer)?;
+                    self.commit = context;
+                }
+                self.pfx = pfx;
+                self.run.push(self.render(&self.pfx, line));
+            }
+            Some(pfx) => {
+                self.flush(writer)?;
+                writeln!(writer, "{}", self.render(&pfx, line))?;
+            }
+            None => {
+                self.flush(writer)?;
+                writeln!(writer, "{}", line)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        let run = std::mem::take(&mut self.run);
+        match self.fold {
+            // a summary in place of one line saves no rows, so it always stands for two or more
+            Some(n) if run.len() > n + 1 => {
+                let tail = n / 2;
+                for line in &run[..n - tail] {
+                    writeln!(writer, "{}", line)?;
+                }
+                let commit = match self.pfx.trim_end() {
+                    "" => self.commit.as_deref().unwrap_or_default(),
+                    pfx => pfx,
+                };
+                writeln!(
+                    writer,
+                    "{:width$}  … {} lines from {} …",
+                    "",
+                    run.len() - n,
+                    commit,
+                    width = strip_ansi_escapes::strip_str(commit).chars().count()
+                )?;
+                for line in &run[run.len() - tail..] {
+                    writeln!(writer, "{}", line)?;
+                }
+            }
+            _ => {
+                for line in &run {
+                    writeln!(writer, "{}", line)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
 
 /// Annotate each line of a diff with the commit-id that last touched it.
 ///
@@ -13,280 +411,6067 @@ use std::thread::ScopedJoinHandle;
 pub struct DiffAnnotator {
     inner: Option<Vec<String>>,
     rev: String,
+    boundary: Option<String>,
     format: Option<String>,
     commits: Vec<String>,
-    candidates: HashSet<String>,
+    candidates: HashMap<String, usize>,
     file: Option<String>,
     start: u32,
     offset: u32,
     maxlen: usize,
+    fold: Option<usize>,
+    cached: bool,
+    abbrev: usize,
+    coverage: Option<Vec<FileHunks>>,
+    squash: bool,
+    raw: Vec<(String, String)>,
+    color_by: Option<ColorBy>,
+    info: HashMap<String, CommitInfo>,
+    notes_file: Option<PathBuf>,
+    notes: Option<Vec<String>>,
+    blob: Option<String>,
+    new_line: u32,
+    wrap: Option<usize>,
+    contents: Option<PathBuf>,
+    now: Option<u64>,
+    hunk_authors: bool,
+    theme: Theme,
+    incremental: bool,
+    squash_whitespace: bool,
+    post_apply: bool,
+    applied: Option<String>,
+    verify: bool,
+    warnings: Vec<String>,
+    columns: Vec<Column>,
+    widths: Vec<usize>,
+    ignore_revs: Vec<String>,
+    stat: bool,
+    summary_format: Option<String>,
+    blamed_lines: usize,
+    blamed_files: HashSet<String>,
+    hunk_seen: bool,
+    hunk_left: (u32, u32),
+    candidate_sort: CandidateSort,
+    batch: bool,
+    batched: HashMap<String, Vec<String>>,
+    uncommitted: usize,
+    context_column: ContextColumn,
+    subject_width: usize,
+    max_age: Option<u64>,
+    old_lines: usize,
+    old_commits: Vec<String>,
+    separator: Option<String>,
+    records: Vec<HashMap<String, usize>>,
+    json: bool,
+    first_only: bool,
+    shown: HashSet<String>,
+    new_file: Option<String>,
+    changed_by: Option<String>,
+    changed_files: Option<HashSet<String>>,
+    side: Side,
+    side_width: usize,
+    stable_colors: bool,
+    skip_extensions: Vec<String>,
+    header_context: bool,
+    source: Option<String>,
+    file_report: Option<Vec<(String, HashMap<String, usize>)>>,
+    symlink: bool,
+    count_only: bool,
+    parents: usize,
+    ignore_revs_file: Option<PathBuf>,
+    require_clean: bool,
+    ignore_whitespace: bool,
+    distances: HashMap<String, usize>,
+    src_prefixes: Vec<String>,
+    dst_prefixes: Vec<String>,
+    parallel: usize,
+    ref_numbers: bool,
+    ref_width: usize,
+    cache_file: Option<PathBuf>,
+    cache_key: Option<String>,
+    cache_dirty: bool,
+    git_dir: Option<PathBuf>,
+    rev_sha: String,
+    first_parent: bool,
+    worktree: bool,
+    max_candidates: Option<usize>,
+    candidate_date: Option<CandidateDate>,
+    link_url: Option<String>,
+    candidate_color: bool,
+    ancestor_marker: char,
+    unknown_marker: char,
+    added_marker: char,
+    index_file: Option<PathBuf>,
+    exact_abbrev: bool,
+    runner: Runner,
+    debug: bool,
+    unified: bool,
+    command: Option<String>,
 }
 
 impl DiffAnnotator {
     const ABBREV: usize = 6;
+    const SUBJECT_WIDTH: usize = 20;
+    const MAX_STDERR: usize = 4096;
+    const SIDE_WIDTH: usize = 80;
+    const CACHE_HEADER: &'static str = "blaming-diff-filter blame cache 1";
+    const MNEMONIC_PREFIXES: [&'static str; 4] = ["c/", "i/", "w/", "o/"];
+    const SUMMARY_FORMAT: &'static str =
+        "{lines} lines blamed to {commits} commits in {files} files";
 
     /// Create a new `DiffAnnotator`.
     ///
     /// * `inner` - An optional inner diff filter to process the diff output before annotating it.
     /// * `back_to` - An optional commit-id to blame up to a common ancestor.
+    /// * `as_of` - An optional date to blame the commit that was `HEAD` at that time.
+    /// * `format` - An optional git format-string to print candidate commits with.
     pub fn new(
         inner: Option<Vec<String>>,
         back_to: Option<String>,
+        as_of: Option<String>,
+        format: Option<String>,
+    ) -> io::Result<Self> {
+        Self::new_in(None, inner, back_to, as_of, format)
+    }
+
+    /// Create a new `DiffAnnotator` for a repository other than the current directory.
+    ///
+    /// * `git_dir` - An optional path to run git in, as with `git -C`; paths of the diff and
+    ///   relative paths passed to git are taken relative to it.
+    /// * `inner` - An optional inner diff filter to process the diff output before annotating it.
+    /// * `back_to` - An optional commit-id to blame up to a common ancestor.
+    /// * `as_of` - An optional date to blame the commit that was `HEAD` at that time.
+    /// * `format` - An optional git format-string to print candidate commits with.
+    pub fn new_in(
+        git_dir: Option<PathBuf>,
+        inner: Option<Vec<String>>,
+        back_to: Option<String>,
+        as_of: Option<String>,
+        format: Option<String>,
+    ) -> io::Result<Self> {
+        Self::new_with_runner(git_dir, inner, back_to, as_of, format, Command::output)
+    }
+
+    /// Create a new `DiffAnnotator` running git commands through a function, from resolving
+    /// the revisions to blame on.
+    ///
+    /// * `git_dir` - An optional path to run git in, as with `git -C`.
+    /// * `inner` - An optional inner diff filter to process the diff output before annotating it.
+    /// * `back_to` - An optional commit-id to blame up to a common ancestor.
+    /// * `as_of` - An optional date to blame the commit that was `HEAD` at that time.
+    /// * `format` - An optional git format-string to print candidate commits with.
+    /// * `runner` - Runs a command to completion in place of `Command::output`.
+    pub fn new_with_runner(
+        git_dir: Option<PathBuf>,
+        inner: Option<Vec<String>>,
+        back_to: Option<String>,
+        as_of: Option<String>,
         format: Option<String>,
+        runner: impl Fn(&mut Command) -> io::Result<Output> + Send + Sync + 'static,
     ) -> io::Result<Self> {
+        let runner: Runner = Arc::new(runner);
+        let (rev, rev_sha, boundary) =
+            Self::make_blame_rev(&runner, git_dir.as_deref(), back_to, as_of)?;
+        let abbrev = Self::config_abbrev(&runner, git_dir.as_deref());
         Ok(DiffAnnotator {
             inner,
-            rev: Self::make_blame_rev(back_to)?,
+            rev,
+            boundary,
             format,
             commits: Vec::new(),
-            candidates: HashSet::new(),
+            candidates: HashMap::new(),
             file: None,
             start: 0,
             offset: 0,
             maxlen: 0,
+            fold: None,
+            cached: false,
+            abbrev,
+            coverage: None,
+            squash: false,
+            raw: Vec::new(),
+            color_by: None,
+            info: HashMap::new(),
+            notes_file: None,
+            notes: None,
+            blob: None,
+            new_line: 0,
+            wrap: None,
+            contents: None,
+            now: None,
+            hunk_authors: false,
+            theme: Theme::default(),
+            incremental: false,
+            squash_whitespace: false,
+            post_apply: false,
+            applied: None,
+            verify: false,
+            warnings: Vec::new(),
+            columns: vec![Column::Hash],
+            widths: Vec::new(),
+            ignore_revs: Vec::new(),
+            stat: false,
+            summary_format: None,
+            blamed_lines: 0,
+            blamed_files: HashSet::new(),
+            hunk_seen: false,
+            hunk_left: (0, 0),
+            candidate_sort: CandidateSort::default(),
+            batch: false,
+            batched: HashMap::new(),
+            uncommitted: 0,
+            context_column: ContextColumn::default(),
+            subject_width: Self::SUBJECT_WIDTH,
+            max_age: None,
+            old_lines: 0,
+            old_commits: Vec::new(),
+            separator: None,
+            records: Vec::new(),
+            json: false,
+            first_only: false,
+            shown: HashSet::new(),
+            new_file: None,
+            changed_by: None,
+            changed_files: None,
+            side: Side::default(),
+            side_width: Self::SIDE_WIDTH,
+            stable_colors: false,
+            skip_extensions: Vec::new(),
+            header_context: false,
+            source: None,
+            file_report: None,
+            symlink: false,
+            count_only: false,
+            parents: 0,
+            ignore_revs_file: None,
+            require_clean: false,
+            ignore_whitespace: false,
+            distances: HashMap::new(),
+            src_prefixes: Self::default_prefixes("a/"),
+            dst_prefixes: Self::default_prefixes("b/"),
+            parallel: 1,
+            ref_numbers: false,
+            ref_width: 1,
+            cache_file: None,
+            cache_key: None,
+            cache_dirty: false,
+            git_dir,
+            rev_sha,
+            first_parent: false,
+            worktree: false,
+            max_candidates: None,
+            candidate_date: None,
+            link_url: None,
+            candidate_color: true,
+            ancestor_marker: '·',
+            unknown_marker: '?',
+            added_marker: '+',
+            index_file: None,
+            exact_abbrev: false,
+            runner,
+            debug: false,
+            unified: false,
+            command: None,
         })
     }
 
-    fn check_output(cmd: &mut Command) -> io::Result<String> {
-        let desc = format!("{cmd:?}");
-        let output = cmd
-            .output()
-            .map_err(|e| io::Error::new(e.kind(), desc.clone()))?;
-        if output.status.success() {
-            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("{desc}: {}", String::from_utf8_lossy(&output.stderr)),
-            ))
+    /// Fold runs of context lines from the same commit.
+    ///
+    /// * `fold` - Maximum number of consecutive same-commit context lines to show; longer runs
+    ///   are collapsed to a summary line.
+    pub fn with_fold_context(mut self, fold: Option<usize>) -> Self {
+        self.fold = fold;
+        self
+    }
+
+    /// Blame the staged state of files instead of `HEAD`.
+    ///
+    /// * `cached` - Annotate the index content, as diffed by `git diff` or `git add -p`.
+    pub fn with_cached(mut self, cached: bool) -> Self {
+        self.cached = cached;
+        self
+    }
+
+    /// Use another index than the repository's.
+    ///
+    /// * `index` - An index file passed to git as `GIT_INDEX_FILE`, such as for `with_cached`.
+    pub fn with_index_file(mut self, index: Option<PathBuf>) -> Self {
+        self.index_file = index;
+        self
+    }
+
+    /// Set the minimum length of displayed commit-ids.
+    ///
+    /// * `abbrev` - Minimum commit-id length, git may still use longer ids to keep them unique;
+    ///   without it, `core.abbrev` is used if configured. Clamped to 4 to 40, as git does.
+    pub fn with_min_abbrev(mut self, abbrev: Option<usize>) -> Self {
+        if let Some(abbrev) = abbrev {
+            self.abbrev = abbrev.clamp(4, 40);
+            self.exact_abbrev = false;
         }
+        self
     }
 
-    fn rev_parse(rev: &str) -> io::Result<String> {
-        Self::check_output(Command::new("git").arg("rev-parse").arg(rev))
+    /// Run git commands through a function, such as to log or count them.
+    ///
+    /// * `runner` - Runs a command to completion in place of `Command::output`; it applies to
+    ///   commands run after building, use `new_with_runner` to also resolve the revisions.
+    pub fn with_runner(
+        mut self,
+        runner: impl Fn(&mut Command) -> io::Result<Output> + Send + Sync + 'static,
+    ) -> Self {
+        self.runner = Arc::new(runner);
+        self
     }
 
-    fn make_blame_rev(back_to: Option<String>) -> io::Result<String> {
-        if let Some(back_to) = back_to {
-            if Self::rev_parse(&back_to)? == Self::rev_parse("HEAD")? {
-                // ignore when currently on --back-to branch
-                return Ok("HEAD".to_string());
-            }
-            return Ok(Self::check_output(
-                Command::new("git")
-                    .arg("merge-base")
-                    .arg("HEAD")
-                    .arg(&back_to),
-            )? + "..");
+    /// Set the exact length of displayed commit-ids.
+    ///
+    /// * `abbrev` - Commit-id length, ids are cut to it even where they become ambiguous;
+    ///   without it, the minimum length applies. Clamped to 4 to 40, as git does.
+    pub fn with_abbrev(mut self, abbrev: Option<usize>) -> Self {
+        if let Some(abbrev) = abbrev {
+            self.abbrev = abbrev.clamp(4, 40);
+            self.exact_abbrev = true;
         }
-        Ok("HEAD".to_string())
+        self
     }
 
-    fn parse_hunk(&mut self, line: &str) -> u32 {
-        // @@ -36,7 +36,7 @@
-        let mut parts = line.split_whitespace();
-        let mut old = parts.nth(1).unwrap()[1..].split(',');
-        self.start = old.next().unwrap().parse::<u32>().unwrap();
-        let count = old.next().unwrap().parse::<u32>().unwrap();
-        self.start + count
+    /// Report which lines of each changed file the diff touches.
+    ///
+    /// * `coverage` - Print the fraction and ranges of lines outside of any hunk.
+    pub fn with_coverage(mut self, coverage: bool) -> Self {
+        self.coverage = coverage.then(Vec::new);
+        self
     }
 
-    fn blame_hunk(&mut self, header: &str) -> io::Result<()> {
-        let end = self.parse_hunk(header);
-        self.commits = Self::check_output(
-            Command::new("git")
-                .arg("blame")
-                .arg(&self.rev)
-                .arg(format!("--abbrev={}", Self::ABBREV - 1))
-                .arg("-L")
-                .arg(&format!("{},{}", self.start, end))
-                .arg(self.file.as_deref().unwrap()),
-        )?
-        .lines()
-        .map(|line| line.split_whitespace().next().unwrap().to_string())
-        .collect();
-        self.maxlen = self.commits.iter().fold(Self::ABBREV, |acc, commit| {
-            if commit.len() > acc {
-                commit.len()
-            } else {
-                acc
-            }
-        });
-        self.offset = self.start;
-        Ok(())
+    /// Collapse `fixup!`/`squash!` commits into their target in the candidate list.
+    ///
+    /// * `squash` - Omit candidates whose subject references another candidate.
+    pub fn with_squash_candidates(mut self, squash: bool) -> Self {
+        self.squash = squash;
+        self
     }
 
-    fn lookup_commit(&self) -> Option<String> {
-        if self.start <= self.offset && self.offset < self.start + self.commits.len() as u32 {
-            return Some(self.commits[(self.offset - self.start) as usize].clone());
-        }
-        None
+    /// Colorize annotated commit-ids.
+    ///
+    /// * `color_by` - The commit property to derive the color from.
+    pub fn with_color_by(mut self, color_by: Option<ColorBy>) -> Self {
+        self.color_by = color_by;
+        self
     }
 
-    fn process_line(&mut self, line: &str) -> io::Result<Option<String>> {
-        let line = strip_ansi_escapes::strip_str(line);
-        if let Some(path) = line.strip_prefix("--- ") {
-            // for new files this can be /dev/null, so ignore anything not starting with "a/"
-            self.file = path.strip_prefix("a/").map(str::to_string);
-            Ok(None)
-        } else if line.starts_with("+++ ") {
-            Ok(None)
-        } else if line.starts_with("@@ ") {
-            if self.file.is_some() {
-                self.blame_hunk(&line)?;
-            } else {
-                self.commits.clear();
-            }
-            Ok(None)
-        } else if line.starts_with(' ') || line.starts_with('-') {
-            if let Some(commit) = self.lookup_commit() {
-                self.offset += 1;
-                if commit.starts_with('^') || commit.chars().all(|c| c == '0') {
-                    Ok(Some(format!("{} ", "·".repeat(self.maxlen))))
-                } else {
-                    self.candidates.insert(commit.clone());
-                    Ok(Some(format!("{} ", commit)))
-                }
-            } else {
-                self.offset += 1;
-                Ok(Some(format!("{} ", "?".repeat(self.maxlen))))
-            }
-        } else if line.starts_with('+') {
-            Ok(Some(format!("{} ", "+".repeat(self.maxlen))))
-        } else {
-            Ok(None)
+    /// Write the blamed commit of each context line to a file.
+    ///
+    /// * `notes_file` - The file to write lines of `<new-blob> <new-line> <commit-id>` to, for
+    ///   importing line provenance into notes or review tools.
+    pub fn with_emit_notes(mut self, notes_file: Option<PathBuf>) -> Self {
+        self.notes = notes_file.as_ref().map(|_| Vec::new());
+        self.notes_file = notes_file;
+        self
+    }
+
+    /// Soft-wrap long annotated lines.
+    ///
+    /// * `wrap` - The width to wrap lines at, continuation rows are indented past the column.
+    pub fn with_wrap(mut self, wrap: Option<usize>) -> Self {
+        self.wrap = wrap;
+        self
+    }
+
+    /// Select the content to blame.
+    ///
+    /// * `source` - What the old side of the diff was taken from; `Index` is the same as
+    ///   `with_cached`, `Worktree` blames the files in the work tree. Without it, the current
+    ///   setting is kept.
+    pub fn with_source(mut self, source: Option<Source>) -> Self {
+        if let Some(source) = source {
+            self.cached = source == Source::Index;
+            self.worktree = source == Source::Worktree;
         }
+        self
     }
 
-    fn wrapping_diff<R: BufRead, W: Write + Sync + Send>(
-        &mut self,
-        reader: R,
-        mut writer: W,
-    ) -> io::Result<()> {
-        if let Some(inner) = &self.inner {
-            let cmd = Command::new(&inner[0])
-                .args(&inner[1..])
-                .stdin(Stdio::piped())
-                .stdout(Stdio::piped())
-                .spawn()
-                .map_err(|e| io::Error::new(e.kind(), format!("Inner cmd: {}", inner[0])))?;
+    /// Blame against externally provided file content.
+    ///
+    /// * `contents` - A file with the content to blame instead of the file in `HEAD`, as with
+    ///   `git blame --contents`. It applies to all files, so is useful for single-file diffs.
+    pub fn with_contents(mut self, contents: Option<PathBuf>) -> Self {
+        self.contents = contents;
+        self
+    }
 
-            let (tx, rx) = mpsc::channel::<Option<String>>();
-            let stdout = BufReader::new(cmd.stdout.unwrap());
-            let mut stdin = cmd.stdin.unwrap();
+    /// Fix the reference time for age computations.
+    ///
+    /// * `now` - The current time to assume, in seconds since the epoch.
+    pub fn with_now(mut self, now: Option<u64>) -> Self {
+        self.now = now;
+        self
+    }
 
-            std::thread::scope(|s| {
-                let t: ScopedJoinHandle<io::Result<()>> = s.spawn(move || {
-                    for line in stdout.lines() {
-                        match rx.recv() {
-                            Ok(Some(pfx)) => write!(writer, "{}", pfx)?,
-                            Ok(None) => (),
-                            Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
-                        }
-                        writeln!(writer, "{}", line?)?;
-                    }
-                    Ok(())
-                });
-                for line in reader.lines() {
-                    let line = line?;
-                    tx.send(self.process_line(&line)?)
-                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-                    writeln!(stdin, "{}", line)?;
-                }
-                drop(stdin);
-                t.join().unwrap()
-            })?;
+    /// Append the number of distinct authors of blamed lines to hunk headers.
+    ///
+    /// * `hunk_authors` - Count authors of the commits blamed for each hunk.
+    pub fn with_hunk_authors(mut self, hunk_authors: bool) -> Self {
+        self.hunk_authors = hunk_authors;
+        self
+    }
+
+    /// Select the color palette for colorized annotations.
+    ///
+    /// * `theme` - The named palette to use.
+    pub fn with_theme(mut self, theme: Theme) -> Self {
+        self.theme = theme;
+        self
+    }
+
+    /// Use the incremental blame output format.
+    ///
+    /// * `incremental` - Parse `git blame --incremental` output, which git streams as it
+    ///   blames commits. Commit-ids are abbreviated to a fixed length.
+    pub fn with_incremental(mut self, incremental: bool) -> Self {
+        self.incremental = incremental;
+        self
+    }
+
+    /// Collapse hunks only changing whitespace.
+    ///
+    /// * `squash` - Replace the lines of hunks whose removed and added lines differ only in
+    ///   leading or trailing whitespace with a single summary line.
+    pub fn with_squash_whitespace_only_hunks(mut self, squash: bool) -> Self {
+        self.squash_whitespace = squash;
+        self
+    }
+
+    /// Blame the result of applying the diff.
+    ///
+    /// * `post_apply` - Apply the diff on top of the blamed commit to a scratch commit and blame
+    ///   the new side of each hunk, attributing added lines to that commit. The work tree, index
+    ///   and refs stay untouched, but the commit and its objects are written to the repository,
+    ///   unreferenced until `git gc` prunes them.
+    pub fn with_post_apply(mut self, post_apply: bool) -> Self {
+        self.post_apply = post_apply;
+        self
+    }
+
+    /// Warn about annotations that could not be determined.
+    ///
+    /// * `verify` - Print a warning for each hunk where blame returned fewer lines than the hunk
+    ///   spans, such as for a diff against a different version of the file.
+    pub fn with_verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Print debug messages about blaming.
+    ///
+    /// * `debug` - Print a debug message for each hunk where blame returned fewer lines than the
+    ///   hunk spans; with `with_verify`, these are warnings instead.
+    pub fn with_debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    /// Select the columns of the annotation prefix.
+    ///
+    /// * `columns` - The columns to show, each aligned to its own width. Without any, only the
+    ///   commit-id is shown.
+    pub fn with_columns(mut self, columns: Vec<Column>) -> Self {
+        if !columns.is_empty() {
+            self.columns = columns;
         }
-        Ok(())
+        self
     }
 
-    fn simple_diff<R: BufRead, W: Write + Sync + Send>(
-        &mut self,
-        reader: R,
-        mut writer: W,
-    ) -> io::Result<()> {
-        for line in reader.lines() {
-            let line = line?;
-            if let Some(pfx) = self.process_line(&line)? {
-                write!(writer, "{}", pfx)?;
-            }
-            writeln!(writer, "{}", line)?;
+    /// Ignore commits when blaming.
+    ///
+    /// * `revs` - Commits to pass to `git blame --ignore-rev`, their changes get blamed to the
+    ///   commits responsible before them.
+    pub fn with_ignore_revs(mut self, revs: Vec<String>) -> Self {
+        self.ignore_revs = revs;
+        self
+    }
+
+    /// Ignore commits listed in a file when blaming.
+    ///
+    /// * `file` - A file to pass to `git blame --ignore-revs-file`, with one commit per line;
+    ///   without it, `blame.ignoreRevsFile` is used if configured.
+    pub fn with_ignore_revs_file(mut self, file: Option<PathBuf>) -> Self {
+        self.ignore_revs_file = file.or_else(|| {
+            self.run(
+                self.git()
+                    .arg("config")
+                    .arg("--path")
+                    .arg("blame.ignoreRevsFile"),
+            )
+            .ok()
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+        });
+        self
+    }
+
+    /// Ignore whitespace changes when blaming.
+    ///
+    /// * `ignore_whitespace` - If true, pass `-w` to `git blame`, so lines only reindented by a
+    ///   commit get blamed to the commit that introduced their content.
+    pub fn with_ignore_whitespace(mut self, ignore_whitespace: bool) -> Self {
+        self.ignore_whitespace = ignore_whitespace;
+        self
+    }
+
+    /// Follow only the first parent of merges when blaming.
+    ///
+    /// * `first_parent` - If true, pass `--first-parent` to `git blame`, so lines merged in from
+    ///   side branches get blamed to the merge commit.
+    pub fn with_first_parent(mut self, first_parent: bool) -> Self {
+        self.first_parent = first_parent;
+        self
+    }
+
+    /// Set the characters filling the column of lines without a commit-id.
+    ///
+    /// * `ancestor` - For lines from before the `back_to` ancestor or not committed yet, `·` by
+    ///   default.
+    /// * `unknown` - For lines that could not be blamed, `?` by default.
+    /// * `added` - For added lines, `+` by default.
+    pub fn with_markers(mut self, ancestor: char, unknown: char, added: char) -> Self {
+        self.ancestor_marker = ancestor;
+        self.unknown_marker = unknown;
+        self.added_marker = added;
+        self
+    }
+
+    /// Annotate diffs without a/ and b/ prefixes on paths.
+    ///
+    /// * `no_prefix` - If true, expect paths as written by `git diff --no-prefix` or with
+    ///   `diff.noprefix` set.
+    pub fn with_no_prefix(mut self, no_prefix: bool) -> Self {
+        if no_prefix {
+            self.src_prefixes = vec![String::new()];
+            self.dst_prefixes = vec![String::new()];
         }
-        Ok(())
+        self
     }
 
-    /// Annotate a diff with the commit-id that last touched each line.
+    /// Annotate diffs with custom prefixes on paths.
     ///
-    /// * `reader` - A reader for the diff to annotate.
-    /// * `writer` - A writer for the annotated diff.
-    pub fn annotate_diff<R: BufRead, W: Write + Sync + Send, CW: Write>(
-        &mut self,
-        reader: R,
-        writer: W,
-        mut cand_writer: CW,
-    ) -> io::Result<()> {
-        if self.inner.is_some() {
-            self.wrapping_diff(reader, writer)?;
-        } else {
-            self.simple_diff(reader, writer)?;
+    /// * `src` - The prefix of old paths, as set with `git diff --src-prefix`; without it, a/
+    ///   and the prefixes of `diff.mnemonicPrefix` are recognized.
+    /// * `dst` - The prefix of new paths, as set with `git diff --dst-prefix`; without it, b/
+    ///   and the prefixes of `diff.mnemonicPrefix` are recognized.
+    pub fn with_prefixes(mut self, src: Option<String>, dst: Option<String>) -> Self {
+        if let Some(src) = src {
+            self.src_prefixes = vec![src];
         }
-        if let Some(format) = &self.format {
-            let output = Self::check_output(
-                Command::new("git")
-                    .arg("show")
-                    .arg("-s")
-                    .arg("--color")
-                    .arg(format!("--abbrev={}", Self::ABBREV))
-                    .arg(format!("--format=%at {}", format))
-                    .args(&self.candidates),
-            )?;
-            let mut lines: Vec<_> = output.lines().collect();
-            lines.sort_by_key(|line| {
-                line.split_whitespace()
-                    .next()
-                    .unwrap_or("0")
-                    .parse::<u64>()
-                    .unwrap_or(0)
-            });
-            for line in lines {
-                let line = line
-                    .split_whitespace()
-                    .skip(1)
-                    .collect::<Vec<_>>()
-                    .join(" ");
-                writeln!(cand_writer, "{}", line)?;
-            }
+        if let Some(dst) = dst {
+            self.dst_prefixes = vec![dst];
         }
-        Ok(())
+        self
+    }
+
+    /// Number lines by their new side line.
+    ///
+    /// * `ref_numbers` - If true, annotated context and added lines get their line number in the
+    ///   new file in front of the annotation, for citing them in review comments; removed lines
+    ///   get a blank.
+    pub fn with_ref_numbers(mut self, ref_numbers: bool) -> Self {
+        self.ref_numbers = ref_numbers;
+        self
+    }
+
+    /// Keep blames of whole files in a file to reuse them across runs.
+    ///
+    /// * `file` - The cache file, read before and written after annotating. Its blames are only
+    ///   reused for the same blamed commit and blame options, any change of `HEAD` discards them.
+    ///   Not used with `with_cached`, `with_contents`, `with_post_apply` or
+    ///   `with_batch_blame_ranges`.
+    pub fn with_blame_cache_file(mut self, file: Option<PathBuf>) -> Self {
+        self.cache_file = file;
+        self
+    }
+
+    /// Refuse to annotate diffs of files with uncommitted changes.
+    ///
+    /// * `require_clean` - If true, fail before annotating if any file of the diff is modified
+    ///   or untracked in the working tree, as blaming `HEAD` would not match what is on disk.
+    pub fn with_require_clean_tree(mut self, require_clean: bool) -> Self {
+        self.require_clean = require_clean;
+        self
+    }
+
+    /// Write only the number of distinct candidate commits.
+    ///
+    /// * `count_only` - If true, the annotated diff and any reports are replaced by the count.
+    pub fn with_count_only(mut self, count_only: bool) -> Self {
+        self.count_only = count_only;
+        self
+    }
+
+    /// Print a summary of the blamed lines.
+    ///
+    /// * `stat` - Print the number of blamed lines, distinct commits and files after the
+    ///   candidate commits.
+    pub fn with_stat(mut self, stat: bool) -> Self {
+        self.stat = stat;
+        self
+    }
+
+    /// Customize the summary printed with `with_stat`.
+    ///
+    /// * `format` - A format with `{lines}`, `{commits}` and `{files}` placeholders.
+    pub fn with_summary_format(mut self, format: Option<String>) -> Self {
+        self.summary_format = format;
+        self
+    }
+
+    /// Select the order of printed candidate commits.
+    ///
+    /// * `sort` - How to order candidates; commits merged from side branches are ordered by
+    ///   date after any on the first-parent history.
+    pub fn with_candidate_sort(mut self, sort: CandidateSort) -> Self {
+        self.candidate_sort = sort;
+        self
+    }
+
+    /// Blame all hunks of a file at once.
+    ///
+    /// * `batch` - Read the whole diff upfront and pass the hunk ranges of each file to a single
+    ///   blame. Commit-ids are abbreviated to a fixed length, as with `with_incremental`.
+    pub fn with_batch_blame_ranges(mut self, batch: bool) -> Self {
+        self.batch = batch;
+        self
+    }
+
+    /// Blame several files concurrently when blaming all hunks of a file at once.
+    ///
+    /// * `parallel` - Maximum number of files to blame at the same time, the number of CPUs if
+    ///   `None`. The output keeps the order of the diff either way.
+    pub fn with_parallel_files(mut self, parallel: Option<usize>) -> Self {
+        self.parallel = parallel
+            .or_else(|| std::thread::available_parallelism().ok().map(usize::from))
+            .unwrap_or(1)
+            .max(1);
+        self
+    }
+
+    /// Select how context lines get annotated.
+    ///
+    /// * `context_column` - Whether context lines show their commit, a blank column or none.
+    pub fn with_context_column(mut self, context_column: ContextColumn) -> Self {
+        self.context_column = context_column;
+        self
+    }
+
+    /// Set the width of the subject column.
+    ///
+    /// * `width` - Subjects are padded to this width, longer ones get truncated with an ellipsis;
+    ///   a width of 0 is taken as 1, leaving room for the ellipsis.
+    pub fn with_subject_width(mut self, width: Option<usize>) -> Self {
+        self.subject_width = width.unwrap_or(Self::SUBJECT_WIDTH).max(1);
+        self
+    }
+
+    /// Limit the number of printed candidate commits.
+    ///
+    /// * `max` - How many candidates to print after sorting; the rest are summarized in a
+    ///   `… and N more` line.
+    pub fn with_max_candidates(mut self, max: Option<usize>) -> Self {
+        self.max_candidates = max;
+        self
+    }
+
+    /// Color printed candidate commits.
+    ///
+    /// * `color` - If false, pass `--color=never` to `git show`, so colors of the format-string
+    ///   are left out.
+    pub fn with_candidate_color(mut self, color: bool) -> Self {
+        self.candidate_color = color;
+        self
+    }
+
+    /// Show the author date of printed candidate commits.
+    ///
+    /// * `date` - The format of the date put before each candidate line.
+    pub fn with_candidate_date(mut self, date: Option<CandidateDate>) -> Self {
+        self.candidate_date = date;
+        self
+    }
+
+    /// Flag changes to lines not touched for a long time.
+    ///
+    /// * `max_age` - Age in seconds; removed lines last changed by an older commit get a `!`
+    ///   marker after the annotation, and their commits are listed in a warning.
+    pub fn with_max_candidate_age(mut self, max_age: Option<u64>) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Annotate concatenated diffs separated by a delimiter line.
+    ///
+    /// * `separator` - A line separating records; per-file state resets at each, and candidate
+    ///   commits are listed for each record, separated by the same line.
+    pub fn with_record_separator(mut self, separator: Option<String>) -> Self {
+        self.separator = separator;
+        self
+    }
+
+    /// Write the annotated diff as a JSON document instead of a diff.
+    ///
+    /// * `json` - If true, write `{"version": 2, "files": [...], "candidates": [...]}` with the
+    ///   annotated lines grouped by file, each line also naming its file.
+    pub fn with_json(mut self, json: bool) -> Self {
+        self.json = json;
+        self
+    }
+
+    /// Append the commit of the function context of hunk headers.
+    ///
+    /// * `header_context` - If true, the nearest line before a hunk starting with the context
+    ///   shown after `@@` is blamed, and its commit-id appended to the header.
+    pub fn with_blame_header_context(mut self, header_context: bool) -> Self {
+        self.header_context = header_context;
+        self
+    }
+
+    /// Report the commits of removed lines per file, after the candidates.
+    ///
+    /// * `file_report` - If true, a section per file lists the commits with their line counts.
+    pub fn with_file_report(mut self, file_report: bool) -> Self {
+        self.file_report = file_report.then(Vec::new);
+        self
+    }
+
+    /// Pass through files with some extensions without annotating them.
+    ///
+    /// * `extensions` - Extensions without the leading dot, such as `lock` or `min.js`.
+    pub fn with_skip_extensions(mut self, extensions: Vec<String>) -> Self {
+        self.skip_extensions = extensions;
+        self
+    }
+
+    /// Annotate only the first occurrence of each commit across the whole diff.
+    ///
+    /// * `first_only` - If true, later lines of an already shown commit get a blank column.
+    pub fn with_first_only(mut self, first_only: bool) -> Self {
+        self.first_only = first_only;
+        self
+    }
+
+    /// Link commit-ids to a web page of the commit.
+    ///
+    /// * `url` - A URL template with `{commit}` replaced by the full sha; commit-ids get wrapped
+    ///   in OSC-8 hyperlink escapes to it, keeping their abbreviated text.
+    pub fn with_link_url(mut self, url: Option<String>) -> Self {
+        self.link_url = url;
+        self
+    }
+
+    /// Derive commit colors from the full sha instead of the abbreviated commit-id.
+    ///
+    /// * `stable_colors` - If true, the same commit gets the same color across repositories,
+    ///   machines and abbreviation lengths.
+    pub fn with_stable_colors(mut self, stable_colors: bool) -> Self {
+        self.stable_colors = stable_colors;
+        self
+    }
+
+    /// Put the annotation column on one side of the content.
+    ///
+    /// * `side` - The side of the column; on the right, content is padded to the side width.
+    pub fn with_side(mut self, side: Side) -> Self {
+        self.side = side;
+        self
+    }
+
+    /// Width to pad lines with the annotation column on the right to.
+    ///
+    /// * `width` - The width of padded lines including the column, 80 if not given.
+    pub fn with_side_width(mut self, width: Option<usize>) -> Self {
+        self.side_width = width.unwrap_or(Self::SIDE_WIDTH);
+        self
+    }
+
+    /// Annotate only files touched by a commit.
+    ///
+    /// * `commit` - A commit whose changed files are annotated; lines of other files are passed
+    ///   through without an annotation column.
+    pub fn with_only_files_changed_by(mut self, commit: Option<String>) -> Self {
+        self.changed_by = commit;
+        self
+    }
+
+    fn run(&self, cmd: &mut Command) -> io::Result<String> {
+        Self::check_output(&self.runner, cmd)
+    }
+
+    fn check_output(run: &Runner, cmd: &mut Command) -> io::Result<String> {
+        let desc = format!("{cmd:?}");
+        let output = run(cmd).map_err(|e| io::Error::new(e.kind(), desc.clone()))?;
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        } else {
+            Err(io::Error::other(format!(
+                "{desc}: {}",
+                Self::stderr_message(&output.stderr)
+            )))
+        }
+    }
+
+    fn stderr_message(stderr: &[u8]) -> String {
+        let mut message = String::from_utf8_lossy(stderr).into_owned();
+        if message.len() > Self::MAX_STDERR {
+            let mut end = Self::MAX_STDERR;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            let omitted = message.len() - end;
+            message.truncate(end);
+            message.push_str(&format!("... ({omitted} more bytes)"));
+        }
+        message
+    }
+
+    fn git_in(git_dir: Option<&Path>) -> Command {
+        let mut cmd = Command::new("git");
+        if let Some(dir) = git_dir {
+            cmd.arg("-C").arg(dir);
+        }
+        cmd
+    }
+
+    fn git(&self) -> Command {
+        let mut cmd = Self::git_in(self.git_dir.as_deref());
+        if let Some(index) = &self.index_file {
+            cmd.env("GIT_INDEX_FILE", index);
+        }
+        cmd
+    }
+
+    #[cfg(feature = "git2")]
+    fn open_repo(git_dir: Option<&Path>) -> io::Result<git2::Repository> {
+        match git_dir {
+            Some(dir) => git2::Repository::discover(dir),
+            None => git2::Repository::open_from_env(),
+        }
+        .map_err(io::Error::other)
+    }
+
+    #[cfg(not(feature = "git2"))]
+    fn rev_parse(run: &Runner, git_dir: Option<&Path>, rev: &str) -> io::Result<String> {
+        Self::check_output(run, Self::git_in(git_dir).arg("rev-parse").arg(rev))
+    }
+
+    /// Resolves in-process through libgit2, producing the same ids as `git rev-parse`.
+    #[cfg(feature = "git2")]
+    fn rev_parse(_run: &Runner, git_dir: Option<&Path>, rev: &str) -> io::Result<String> {
+        let repo = Self::open_repo(git_dir)?;
+        let object = repo
+            .revparse_single(rev)
+            .map_err(|e| io::Error::other(format!("{rev}: {}", e.message())))?;
+        Ok(object.id().to_string())
+    }
+
+    fn config_abbrev(run: &Runner, git_dir: Option<&Path>) -> usize {
+        let value = Self::check_output(
+            run,
+            Self::git_in(git_dir)
+                .arg("config")
+                .arg("--get")
+                .arg("core.abbrev"),
+        );
+        match value.as_deref() {
+            Ok("no") => 40,
+            // unset, auto and lengths git would reject keep the default
+            Ok(value) => value
+                .parse()
+                .ok()
+                .filter(|abbrev| (4..=40).contains(abbrev))
+                .unwrap_or(Self::ABBREV),
+            Err(_) => Self::ABBREV,
+        }
+    }
+
+    fn make_blame_rev(
+        run: &Runner,
+        git_dir: Option<&Path>,
+        back_to: Option<String>,
+        as_of: Option<String>,
+    ) -> io::Result<(String, String, Option<String>)> {
+        let rev = match as_of {
+            Some(date) => {
+                let rev = Self::check_output(
+                    run,
+                    Self::git_in(git_dir)
+                        .arg("rev-list")
+                        .arg("-1")
+                        .arg(format!("--before={date}"))
+                        .arg("HEAD"),
+                )?;
+                if rev.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("no commit before {date}"),
+                    ));
+                }
+                rev
+            }
+            None => "HEAD".to_string(),
+        };
+        // resolve once, everything below refers to the same commit
+        let sha = Self::rev_parse(run, git_dir, &rev)?;
+        if let Some(back_to) = back_to {
+            let back_to = match back_to == rev {
+                true => sha.clone(),
+                false => Self::rev_parse(run, git_dir, &back_to)?,
+            };
+            if back_to == sha {
+                // ignore when currently on --back-to branch
+                return Ok((rev, sha, None));
+            }
+            let ancestor = run(Self::git_in(git_dir)
+                .arg("merge-base")
+                .arg("--is-ancestor")
+                .arg(&back_to)
+                .arg(&sha))?;
+            if ancestor.status.success() {
+                // relative refs such as HEAD~5 are the lower bound as is
+                return Ok((rev, sha, Some(back_to)));
+            }
+            let base = Self::check_output(
+                run,
+                Self::git_in(git_dir)
+                    .arg("merge-base")
+                    .arg(&sha)
+                    .arg(&back_to),
+            )?;
+            return Ok((rev, sha, Some(base)));
+        }
+        Ok((rev, sha, None))
+    }
+
+    fn default_prefixes(prefix: &str) -> Vec<String> {
+        [prefix]
+            .into_iter()
+            .chain(Self::MNEMONIC_PREFIXES)
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn parse_path(path: &str, prefixes: &[String]) -> Option<String> {
+        // git appends a tab to paths containing spaces, and C-style quotes special characters
+        let path = path.trim_end_matches('\t');
+        let path = match path.strip_prefix('"').and_then(|p| p.strip_suffix('"')) {
+            Some(quoted) => Self::unquote(quoted),
+            None => path.to_string(),
+        };
+        // for new or deleted files this can be /dev/null, so ignore anything not prefixed
+        prefixes
+            .iter()
+            .find_map(|prefix| path.strip_prefix(prefix.as_str()))
+            .filter(|_| path != "/dev/null")
+            .map(str::to_string)
+    }
+
+    fn unquote(quoted: &str) -> String {
+        let mut bytes = Vec::with_capacity(quoted.len());
+        let mut iter = quoted.bytes();
+        while let Some(b) = iter.next() {
+            if b != b'\\' {
+                bytes.push(b);
+                continue;
+            }
+            match iter.next() {
+                Some(b'a') => bytes.push(0x07),
+                Some(b'b') => bytes.push(0x08),
+                Some(b'f') => bytes.push(0x0c),
+                Some(b'n') => bytes.push(b'\n'),
+                Some(b'r') => bytes.push(b'\r'),
+                Some(b't') => bytes.push(b'\t'),
+                Some(b'v') => bytes.push(0x0b),
+                Some(d @ b'0'..=b'3') => {
+                    // octal escaped byte, such as \303\244 for UTF-8 encoded characters
+                    let octal = [Some(d), iter.next(), iter.next()];
+                    let byte = octal.iter().try_fold(0u8, |acc, d| match d {
+                        Some(d @ b'0'..=b'7') => Some(acc * 8 + (d - b'0')),
+                        _ => None,
+                    });
+                    bytes.push(byte.unwrap_or(b'?'));
+                }
+                Some(b) => bytes.push(b),
+                None => (),
+            }
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    fn combined_parents(line: &str) -> Option<usize> {
+        // @@@ -1,7 -1,7 +1,7 @@@ for two parents, with an @ more per additional parent
+        let ats = line.chars().take_while(|c| *c == '@').count();
+        (ats >= 3 && line[ats..].starts_with(' ')).then(|| ats - 1)
+    }
+
+    fn hunk_ranges(line: &str) -> Option<[&str; 2]> {
+        // @@ -36,7 +36,7 @@ context, hand-edited diffs may lack the space before the context
+        let (ranges, _) = line.strip_prefix("@@")?.split_once("@@")?;
+        let mut ranges = ranges.split_whitespace();
+        match (ranges.next(), ranges.next(), ranges.next()) {
+            (Some(old), Some(new), None) => Some([old, new]),
+            _ => None,
+        }
+    }
+
+    fn parse_range(range: &str) -> Option<(u32, u32)> {
+        // git omits the count of single line ranges, as in "36" for "36,1"
+        let (start, count) = range.split_once(',').unwrap_or((range, "1"));
+        Some((start.parse().ok()?, count.parse().ok()?))
+    }
+
+    fn parse_new_start(line: &str) -> u32 {
+        Self::hunk_ranges(line)
+            .and_then(|[_, new]| new[1..].split(',').next())
+            .and_then(|start| start.parse::<u32>().ok())
+            .unwrap_or(0)
+    }
+
+    fn parse_hunk(&mut self, line: &str) -> io::Result<u32> {
+        let (start, end) = self.hunk_range(line)?;
+        self.start = start;
+        Ok(end)
+    }
+
+    fn hunk_range(&self, line: &str) -> io::Result<(u32, u32)> {
+        let (side, sign) = if self.post_apply { (1, '+') } else { (0, '-') };
+        let range = Self::hunk_ranges(line)
+            .and_then(|ranges| ranges[side].strip_prefix(sign))
+            .and_then(Self::parse_range)
+            .and_then(|(start, count)| Some((start, start.checked_add(count)?)));
+        range.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed hunk header: {line:?}"),
+            )
+        })
+    }
+
+    /// Blame a range of lines with the configured rev and options.
+    ///
+    /// * `file` - The path of the file to blame.
+    /// * `start` - The first line of the range to blame.
+    /// * `end` - The last line of the range to blame, inclusive.
+    ///
+    /// Returns the commit-id for each line in the range.
+    pub fn blame_range(&self, file: &str, start: u64, end: u64) -> io::Result<Vec<String>> {
+        self.blame(file, Some((start, end)))
+    }
+
+    fn blame(&self, file: &str, range: Option<(u64, u64)>) -> io::Result<Vec<String>> {
+        let commits = self.blame_commits(file, range)?;
+        Ok(self.cut_abbrev(commits))
+    }
+
+    fn cut_abbrev(&self, mut commits: Vec<String>) -> Vec<String> {
+        // git grows ambiguous ids past the minimum, an exact length cuts them back
+        if self.exact_abbrev {
+            for commit in &mut commits {
+                commit.truncate(self.abbrev);
+            }
+        }
+        commits
+    }
+
+    fn blame_commits(&self, file: &str, range: Option<(u64, u64)>) -> io::Result<Vec<String>> {
+        #[cfg(feature = "git2")]
+        if !self.cached
+            && !self.worktree
+            && self.contents.is_none()
+            && !self.incremental
+            && self.ignore_revs.is_empty()
+            && self.ignore_revs_file.is_none()
+        {
+            return self.blame_libgit2(file, range);
+        }
+        let output = self.blame_subprocess(file, range.as_slice(), self.incremental)?;
+        if self.incremental {
+            let start = range.map_or(1, |(start, _)| start);
+            return Ok(Self::parse_incremental(&output, start, self.abbrev));
+        }
+        let mut commits = Self::parse_blame(&output);
+        // below the minimum, drop the digits git added past the unique prefix it asked for
+        let extra = Self::blame_abbrev(self.abbrev) + 1 - self.abbrev;
+        if extra > 0 {
+            for commit in &mut commits {
+                commit.truncate(commit.len().saturating_sub(extra));
+            }
+        }
+        Ok(commits)
+    }
+
+    fn prefetch_blames(&mut self, patch: &[u8]) -> io::Result<()> {
+        // collect the hunk ranges of each file, as annotate_line would blame them
+        let mut files: Vec<FileHunks> = Vec::new();
+        let mut file = None;
+        let mut left = (0, 0);
+        for line in patch.lines() {
+            let mut line = strip_ansi_escapes::strip_str(line?);
+            if Self::hunk_body(&mut left, &line) {
+                continue;
+            }
+            line = Self::drop_progress(line);
+            if let Some(path) = line.strip_prefix("--- ").filter(|_| !self.post_apply) {
+                file = Self::parse_path(path, &self.src_prefixes);
+            } else if let Some(path) = line.strip_prefix("+++ ").filter(|_| self.post_apply) {
+                file = Self::parse_path(path, &self.dst_prefixes);
+            } else if let (true, Some(file)) = (line.starts_with("@@ "), &file) {
+                left = Self::hunk_counts(&line).unwrap_or_default();
+                let range = self.hunk_range(&line)?;
+                // a file may show up again later, such as in a series of patches
+                match files.iter_mut().find(|(path, _)| path == file) {
+                    Some((_, ranges)) => ranges.push(range),
+                    None => files.push((file.clone(), vec![range])),
+                }
+            }
+        }
+        // workers take the next file until none are left, results are collected by index
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(Vec::with_capacity(files.len()));
+        let this = &*self;
+        std::thread::scope(|s| {
+            for _ in 0..self.parallel.min(files.len()) {
+                s.spawn(|| {
+                    while let Some((file, ranges)) = files.get(next.fetch_add(1, Ordering::Relaxed))
+                    {
+                        let commits = this.blame_ranges(file, ranges);
+                        results.lock().unwrap().push((file.clone(), commits));
+                    }
+                });
+            }
+        });
+        for (file, commits) in results.into_inner().unwrap() {
+            self.batched.insert(file, commits?);
+        }
+        // with all blames at hand, align the column across the whole diff up front
+        self.maxlen = self.batched_maxlen();
+        Ok(())
+    }
+
+    fn blame_ranges(&self, file: &str, ranges: &[(u32, u32)]) -> io::Result<Vec<String>> {
+        // hunk ranges end exclusive, blamed ranges inclusive
+        let ranges: Vec<_> = ranges
+            .iter()
+            .filter(|&&(start, end)| end > start)
+            .map(|&(start, end)| (start.into(), (end - 1).into()))
+            .collect();
+        if ranges.is_empty() {
+            // without any range, blame would run on the whole file
+            return Ok(Vec::new());
+        }
+        // the incremental format tells the line numbers of the interleaved ranges
+        let output = self.blame_subprocess(file, &ranges, true)?;
+        Ok(self.cut_abbrev(Self::parse_incremental(&output, 1, self.abbrev)))
+    }
+
+    fn load_cache(&mut self) -> io::Result<()> {
+        let Some(path) = &self.cache_file else {
+            return Ok(());
+        };
+        if self.cached || self.worktree || self.contents.is_some() || self.post_apply || self.batch
+        {
+            return Ok(());
+        }
+        // the ignored revs may change between runs without the path changing
+        let ignore_revs_file = match &self.ignore_revs_file {
+            Some(file) => {
+                let blob = self.run(self.git().arg("hash-object").arg("--").arg(file))?;
+                format!("{}@{blob}", file.display())
+            }
+            None => String::new(),
+        };
+        // blames depend on the blamed commit and anything changing what blame reports
+        let key = format!(
+            "{} abbrev={} exact-abbrev={} boundary={} ignore-revs={} ignore-revs-file={} ignore-whitespace={} first-parent={} incremental={}",
+            self.rev_sha,
+            self.abbrev,
+            self.exact_abbrev,
+            self.boundary.as_deref().unwrap_or(""),
+            self.ignore_revs.join(","),
+            ignore_revs_file,
+            self.ignore_whitespace,
+            self.first_parent,
+            self.incremental,
+        );
+        match std::fs::read_to_string(path) {
+            Ok(cache) => {
+                let mut lines = cache.lines();
+                if lines.next() == Some(Self::CACHE_HEADER) && lines.next() == Some(key.as_str()) {
+                    for (file, commits) in lines.filter_map(|line| line.split_once('\t')) {
+                        let commits = commits
+                            .split_whitespace()
+                            .map(|commit| commit.strip_prefix('-').unwrap_or(commit).to_string())
+                            .collect();
+                        self.batched.insert(file.to_string(), commits);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        self.cache_key = Some(key);
+        Ok(())
+    }
+
+    fn save_cache(&self) -> io::Result<()> {
+        let (Some(path), Some(key), true) = (&self.cache_file, &self.cache_key, self.cache_dirty)
+        else {
+            return Ok(());
+        };
+        let mut cache = io::BufWriter::new(std::fs::File::create(path)?);
+        writeln!(cache, "{}", Self::CACHE_HEADER)?;
+        writeln!(cache, "{key}")?;
+        for (file, commits) in &self.batched {
+            if file.contains(['\t', '\n']) {
+                continue;
+            }
+            // unblamed lines are stored as -, to keep the line count
+            let commits: Vec<_> = commits
+                .iter()
+                .map(|commit| if commit.is_empty() { "-" } else { commit })
+                .collect();
+            writeln!(cache, "{file}\t{}", commits.join(" "))?;
+        }
+        cache.flush()
+    }
+
+    fn batched_maxlen(&self) -> usize {
+        self.batched
+            .values()
+            .flatten()
+            .map(String::len)
+            .fold(self.maxlen.max(self.abbrev), usize::max)
+    }
+
+    /// Blames in-process through libgit2, producing the same ids as `git blame --abbrev`.
+    #[cfg(feature = "git2")]
+    fn blame_libgit2(&self, file: &str, range: Option<(u64, u64)>) -> io::Result<Vec<String>> {
+        let repo = Self::open_repo(self.git_dir.as_deref())?;
+        let mut opts = git2::BlameOptions::new();
+        let newest = repo
+            .revparse_single(&self.rev)
+            .and_then(|obj| obj.peel_to_commit())
+            .map_err(io::Error::other)?;
+        opts.newest_commit(newest.id());
+        opts.ignore_whitespace(self.ignore_whitespace);
+        opts.first_parent(self.first_parent);
+        if let Some(boundary) = &self.boundary {
+            opts.oldest_commit(git2::Oid::from_str(boundary).map_err(io::Error::other)?);
+        }
+        // like git, clamp the range to the end of the file
+        let blob = newest
+            .tree()
+            .and_then(|tree| tree.get_path(std::path::Path::new(file)))
+            .and_then(|entry| entry.to_object(&repo))
+            .and_then(|obj| obj.peel_to_blob())
+            .map_err(io::Error::other)?;
+        let last = blob.content().split_inclusive(|&b| b == b'\n').count() as u64;
+        if let Some((start, end)) = range {
+            opts.min_line(start as usize)
+                .max_line(end.min(last) as usize);
+        }
+        let blame = repo
+            .blame_file(std::path::Path::new(file), Some(&mut opts))
+            .map_err(io::Error::other)?;
+        let (start, end) = range.map_or((1, last), |(start, end)| (start, end.min(last)));
+        let mut abbrevs = HashMap::new();
+        (start..=end)
+            .map(|line| {
+                let hunk = blame
+                    .get_line(line as usize)
+                    .ok_or_else(|| io::Error::other(format!("no blame for {file}:{line}")))?;
+                let sha = hunk.final_commit_id().to_string();
+                let len = match abbrevs.get(&sha) {
+                    Some(len) => *len,
+                    None => {
+                        // like git, grow the id until it no longer is ambiguous
+                        let len = (self.abbrev..sha.len())
+                            .find(|len| repo.find_object_by_prefix(&sha[..*len], None).is_ok())
+                            .unwrap_or(sha.len());
+                        *abbrevs.entry(sha.clone()).or_insert(len)
+                    }
+                };
+                Ok(if hunk.is_boundary() {
+                    format!("^{}", &sha[..len - 1])
+                } else {
+                    sha[..len].to_string()
+                })
+            })
+            .collect()
+    }
+
+    fn blame_subprocess(
+        &self,
+        file: &str,
+        ranges: &[(u64, u64)],
+        incremental: bool,
+    ) -> io::Result<String> {
+        let mut cmd = self.git();
+        // lines of ignored revs get blamed to earlier commits, or to the ignored rev itself if
+        // unblamable; keep git from marking them, so ids only ever carry the boundary ^
+        cmd.arg("-c")
+            .arg("blame.markIgnoredLines=false")
+            .arg("-c")
+            .arg("blame.markUnblamableLines=false")
+            .arg("blame");
+        let mut staged = None;
+        if self.cached {
+            // --contents can't be combined with a final rev, it always starts at HEAD
+            let path = Self::scratch_file("staged");
+            std::fs::write(&path, self.show(&format!(":{file}"))?)?;
+            cmd.arg("--contents").arg(&path);
+            staged = Some(path);
+        } else if let Some(contents) = &self.contents {
+            cmd.arg("--contents").arg(contents);
+        } else if self.worktree {
+            // like --cached, blame the content on top of HEAD, with changes not committed yet
+            cmd.arg("--contents").arg(file);
+        }
+        if let Some(boundary) = &self.boundary {
+            cmd.arg(format!("^{boundary}"));
+        }
+        if !self.cached && !self.worktree && self.contents.is_none() {
+            cmd.arg(&self.rev);
+        }
+        for rev in &self.ignore_revs {
+            cmd.arg("--ignore-rev").arg(rev);
+        }
+        if let Some(file) = &self.ignore_revs_file {
+            cmd.arg("--ignore-revs-file").arg(file);
+        }
+        if self.ignore_whitespace {
+            cmd.arg("-w");
+        }
+        if self.first_parent {
+            cmd.arg("--first-parent");
+        }
+        if incremental {
+            cmd.arg("--incremental");
+        } else {
+            cmd.arg(format!("--abbrev={}", Self::blame_abbrev(self.abbrev)));
+        }
+        for (start, end) in ranges {
+            cmd.arg("-L").arg(format!("{},{}", start, end));
+        }
+        let output = self.run(cmd.arg("--").arg(file));
+        if let Some(path) = staged {
+            let _ = std::fs::remove_file(path);
+        }
+        output
+    }
+
+    fn show(&self, spec: &str) -> io::Result<Vec<u8>> {
+        // the content as is, run keeps text only and trims it
+        let output = (self.runner)(self.git().arg("show").arg(spec))?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "git show {spec}: {}",
+                Self::stderr_message(&output.stderr)
+            )));
+        }
+        Ok(output.stdout)
+    }
+
+    fn line_count(&self, file: &str) -> io::Result<u32> {
+        // the lines of the content blame runs on, without blaming them all
+        let dir = self.git_dir.clone().unwrap_or_default();
+        let content = if let Some(contents) = &self.contents {
+            std::fs::read(dir.join(contents))?
+        } else if self.worktree {
+            std::fs::read(dir.join(file))?
+        } else if self.cached {
+            self.show(&format!(":{file}"))?
+        } else {
+            self.show(&format!("{}:{file}", self.rev))?
+        };
+        let unterminated = content.last().is_some_and(|&b| b != b'\n');
+        Ok((content.iter().filter(|&&b| b == b'\n').count() + usize::from(unterminated)) as u32)
+    }
+
+    fn scratch_file(kind: &str) -> PathBuf {
+        // files may be blamed in parallel, each needs its own
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        std::env::temp_dir().join(format!(
+            "blaming-diff-filter-{}-{}.{kind}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    fn blame_abbrev(width: usize) -> usize {
+        // git blame shows n+1 digits for --abbrev=n, leaving room for the ^ of boundary commits
+        // that replaces the last digit, so ask for one less to get ids of exactly the width; git
+        // raises n to at least 4
+        (width - 1).max(4)
+    }
+
+    fn parse_incremental(output: &str, start: u64, abbrev: usize) -> Vec<String> {
+        // entries start with "<sha> <orig-line> <final-line> <count>", followed by headers
+        // shown once per commit, such as "boundary", and end with a "filename" header
+        let mut commits = Vec::new();
+        let mut boundaries = HashSet::new();
+        let mut entry = None;
+        for line in output.lines() {
+            let parts: Vec<_> = line.split(' ').collect();
+            match parts[..] {
+                [sha, _, fin, count] if sha.len() == 40 => {
+                    entry = fin
+                        .parse::<u64>()
+                        .ok()
+                        .zip(count.parse::<u64>().ok())
+                        .map(|(fin, count)| (sha, fin, count));
+                }
+                ["boundary"] => {
+                    if let Some((sha, _, _)) = entry {
+                        boundaries.insert(sha);
+                    }
+                }
+                ["filename", ..] => {
+                    if let Some((sha, fin, count)) = entry.take() {
+                        let commit = if boundaries.contains(sha) {
+                            format!("^{}", &sha[..abbrev - 1])
+                        } else {
+                            sha[..abbrev].to_string()
+                        };
+                        let first = (fin.max(start) - start) as usize;
+                        let end = (fin + count).saturating_sub(start) as usize;
+                        if first < end {
+                            if commits.len() < end {
+                                commits.resize(end, String::new());
+                            }
+                            commits[first..end].fill(commit);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        commits
+    }
+
+    fn parse_blame(output: &str) -> Vec<String> {
+        // keep unparseable lines as empty commit-id to retain line offsets
+        output
+            .lines()
+            .map(|line| line.split_whitespace().next().unwrap_or("").to_string())
+            .collect()
+    }
+
+    fn blame_hunk(&mut self, header: &str) -> io::Result<()> {
+        let end = self.parse_hunk(header)?;
+        if let Some(coverage) = &mut self.coverage {
+            let file = self.file.as_deref().unwrap();
+            match coverage.last_mut() {
+                Some((last, hunks)) if last == file => hunks.push((self.start, end)),
+                _ => coverage.push((file.to_string(), vec![(self.start, end)])),
+            }
+        }
+        let file = self.file.as_deref().unwrap();
+        if self.cache_key.is_some() && !self.batched.contains_key(file) {
+            // blame whole files for the cache, later hunks of the file are sliced from it
+            let commits = self.blame(file, None)?;
+            self.batched.insert(file.to_string(), commits);
+            self.cache_dirty = true;
+        }
+        // hunk ranges end exclusive, blamed ranges inclusive
+        self.commits = match self.batched.get(file) {
+            Some(commits) => {
+                let lines = commits.len().min((end as usize).saturating_sub(1));
+                commits[(self.start as usize).saturating_sub(1).min(lines)..lines].to_vec()
+            }
+            None if end > self.start => {
+                self.blame_range(file, self.start.into(), (end - 1).into())?
+            }
+            None => Vec::new(),
+        };
+        self.blamed_files.insert(file.to_string());
+        // git clamps the range to the end of the file, lines past it get annotated as unknown
+        let expected = (end - self.start) as usize;
+        if self.commits.len() < expected {
+            self.warnings.push(format!(
+                "{file}:{}: blame returned {} of {expected} lines, annotating the rest as unknown",
+                self.start,
+                self.commits.len()
+            ));
+        }
+        // never narrow the column within a diff, so earlier hunks stay aligned
+        self.maxlen = self
+            .commits
+            .iter()
+            .fold(self.maxlen.max(self.abbrev), |acc, commit| {
+                if commit.len() > acc {
+                    commit.len()
+                } else {
+                    acc
+                }
+            });
+        self.offset = self.start;
+        if self.hunk_authors
+            || matches!(self.color_by, Some(ColorBy::Author | ColorBy::Age))
+            || self.stable_colors
+            || self.link_url.is_some()
+            || self.columns.iter().any(|column| *column != Column::Hash)
+            || self.max_age.is_some()
+        {
+            self.lookup_info()?;
+        }
+        if self.columns.contains(&Column::Distance) {
+            self.lookup_distances()?;
+        }
+        self.widths = self.column_widths();
+        Ok(())
+    }
+
+    fn blame_header_context(&mut self, header: &str) -> io::Result<Option<String>> {
+        let context = match header.strip_prefix("@@").and_then(|h| h.split_once("@@")) {
+            Some((_, context)) if !context.trim().is_empty() => context.trim_start(),
+            _ => return Ok(None),
+        };
+        let file = self.file.clone().unwrap();
+        if self.source.is_none() {
+            let spec = if self.cached {
+                format!(":{file}")
+            } else {
+                format!("{}:{file}", self.rev)
+            };
+            self.source = Some(if self.worktree {
+                let dir = self.git_dir.clone().unwrap_or_default();
+                std::fs::read_to_string(dir.join(&file))?
+            } else {
+                self.run(self.git().arg("show").arg(spec))?
+            });
+        }
+        // the context is the start of a line before the hunk, such as a function signature
+        let before = (self.start as usize).saturating_sub(1);
+        let line = self.source.as_ref().and_then(|source| {
+            source
+                .lines()
+                .take(before)
+                .enumerate()
+                .filter(|(_, line)| line.starts_with(context))
+                .last()
+                .map(|(i, _)| i)
+        });
+        match line {
+            Some(line) => {
+                let line = line as u64 + 1;
+                Ok(self.blame_range(&file, line, line)?.into_iter().next())
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn column_widths(&self) -> Vec<usize> {
+        self.columns
+            .iter()
+            .map(|column| match column {
+                Column::Hash => self.maxlen,
+                Column::Author => self
+                    .commits
+                    .iter()
+                    .filter_map(|commit| self.info.get(commit))
+                    .map(|info| info.author.chars().count())
+                    .max()
+                    .unwrap_or(1),
+                Column::Initials => self
+                    .commits
+                    .iter()
+                    .filter_map(|commit| self.info.get(commit))
+                    .map(|info| Self::initials(&info.author).chars().count())
+                    .max()
+                    .unwrap_or(1),
+                Column::Date => 10,
+                Column::RelativeDate => self
+                    .commits
+                    .iter()
+                    .filter_map(|commit| self.info.get(commit))
+                    .map(|info| self.relative_date(info.time).chars().count())
+                    .max()
+                    .unwrap_or(1),
+                Column::Subject => self.subject_width,
+                Column::Age => 3,
+                Column::Distance => self
+                    .commits
+                    .iter()
+                    .filter_map(|commit| self.distances.get(commit))
+                    .map(|distance| distance.to_string().len())
+                    .max()
+                    .unwrap_or(1),
+            })
+            .collect()
+    }
+
+    fn initials(name: &str) -> String {
+        name.split_whitespace()
+            .filter_map(|word| word.chars().next())
+            .flat_map(char::to_uppercase)
+            .collect()
+    }
+
+    fn relative_date(&self, time: u64) -> String {
+        const MINUTE: u64 = 60;
+        const HOUR: u64 = 60 * MINUTE;
+        const DAY: u64 = 24 * HOUR;
+        let age = self.now().saturating_sub(time);
+        let (n, unit) = match age {
+            // switch units late, as git does, to avoid "1 hour ago" for 119 minutes
+            0..90 => (age, "second"),
+            90..5_400 => (age / MINUTE, "minute"),
+            5_400..129_600 => (age / HOUR, "hour"),
+            129_600..1_209_600 => (age / DAY, "day"),
+            1_209_600..6_048_000 => (age / (7 * DAY), "week"),
+            6_048_000..31_536_000 => (age / (30 * DAY), "month"),
+            _ => (age / (365 * DAY), "year"),
+        };
+        let plural = if n == 1 { "" } else { "s" };
+        format!("{n} {unit}{plural} ago")
+    }
+
+    fn format_age(age: u64) -> String {
+        const DAY: u64 = 24 * 60 * 60;
+        match age / DAY {
+            days @ 365.. => format!("{}y", days / 365),
+            days @ 30.. => format!("{}M", days / 30),
+            days @ 7.. => format!("{}w", days / 7),
+            days => format!("{days}d"),
+        }
+    }
+
+    fn format_date(time: u64) -> String {
+        // civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+        let days = time / (24 * 60 * 60) + 719_468;
+        let era = days / 146_097;
+        let doe = days - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + u64::from(month <= 2);
+        format!("{year:04}-{month:02}-{day:02}")
+    }
+
+    fn marker_prefix(&self, marker: char) -> String {
+        let cells: Vec<_> = (0..self.columns.len())
+            .map(|i| marker.to_string().repeat(self.width(i)))
+            .collect();
+        format!("{} ", cells.join(" "))
+    }
+
+    fn commit_prefix(&self, commit: &str) -> String {
+        let info = self.info.get(commit);
+        let cells: Vec<_> = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| {
+                let width = self.width(i);
+                match (column, info) {
+                    (Column::Hash, _) => format!(
+                        "{}{:pad$}",
+                        self.hyperlink(self.colorize(commit), commit),
+                        "",
+                        pad = width.saturating_sub(commit.chars().count())
+                    ),
+                    (Column::Author, Some(info)) => format!("{:width$}", info.author),
+                    (Column::Initials, Some(info)) => {
+                        format!("{:width$}", Self::initials(&info.author))
+                    }
+                    (Column::Date, Some(info)) => Self::format_date(info.time),
+                    (Column::RelativeDate, Some(info)) => {
+                        format!("{:width$}", self.relative_date(info.time))
+                    }
+                    (Column::Subject, Some(info)) => Self::truncate(&info.subject, width),
+                    (Column::Age, Some(info)) => {
+                        format!(
+                            "{:>width$}",
+                            Self::format_age(self.now().saturating_sub(info.time))
+                        )
+                    }
+                    (Column::Distance, _) => match self.distances.get(commit) {
+                        Some(distance) => format!("{distance:>width$}"),
+                        None => self.unknown_marker.to_string().repeat(width),
+                    },
+                    (_, None) => self.unknown_marker.to_string().repeat(width),
+                }
+            })
+            .collect();
+        format!("{} ", cells.join(" "))
+    }
+
+    fn truncate(text: &str, width: usize) -> String {
+        if text.chars().count() > width {
+            let head: String = text.chars().take(width.saturating_sub(1)).collect();
+            format!("{head}…")
+        } else {
+            format!("{text:width$}")
+        }
+    }
+
+    fn width(&self, column: usize) -> usize {
+        // without blame, such as for hunks before any file header, fall back to the id length
+        self.widths.get(column).copied().unwrap_or(self.maxlen)
+    }
+
+    fn lookup_info(&mut self) -> io::Result<()> {
+        let missing: HashSet<_> = self
+            .commits
+            .iter()
+            .filter(|commit| {
+                !commit.starts_with('^')
+                    && !commit.chars().all(|c| c == '0')
+                    && !self.info.contains_key(*commit)
+            })
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let output = self.run(
+            self.git()
+                .arg("show")
+                .arg("-s")
+                .arg("--format=%H %at %an%x00%s")
+                .args(&missing),
+        )?;
+        for line in output.lines() {
+            let mut parts = line.splitn(3, ' ');
+            let sha = parts.next().unwrap_or("");
+            let time = parts.next().unwrap_or("0").parse::<u64>().unwrap_or(0);
+            let (author, subject) = parts
+                .next()
+                .unwrap_or("")
+                .split_once('\0')
+                .unwrap_or_default();
+            if let Some(commit) = missing
+                .iter()
+                .find(|commit| sha.starts_with(commit.as_str()))
+            {
+                let info = CommitInfo {
+                    sha: sha.to_string(),
+                    author: author.to_string(),
+                    time,
+                    subject: subject.to_string(),
+                };
+                self.info.insert(commit.to_string(), info);
+            }
+        }
+        Ok(())
+    }
+
+    fn lookup_distances(&mut self) -> io::Result<()> {
+        for commit in &self.commits {
+            if commit.is_empty()
+                || commit.starts_with('^')
+                || commit.chars().all(|c| c == '0')
+                || self.distances.contains_key(commit)
+            {
+                continue;
+            }
+            let count = self.run(
+                self.git()
+                    .arg("rev-list")
+                    .arg("--count")
+                    .arg(format!("{commit}..{}", self.rev)),
+            )?;
+            let distance = count.parse().map_err(io::Error::other)?;
+            self.distances.insert(commit.clone(), distance);
+        }
+        Ok(())
+    }
+
+    fn now(&self) -> u64 {
+        self.now.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs())
+        })
+    }
+
+    fn colorize(&self, commit: &str) -> String {
+        let color = match self.color_by {
+            // abbreviations vary with the repository, the full sha does not
+            Some(ColorBy::Commit) if self.stable_colors => self
+                .info
+                .get(commit)
+                .map(|info| self.theme.hash_color(&info.sha)),
+            Some(ColorBy::Commit) => Some(self.theme.hash_color(commit)),
+            Some(ColorBy::Author) => self
+                .info
+                .get(commit)
+                .map(|info| self.theme.hash_color(&info.author)),
+            Some(ColorBy::Age) => self
+                .info
+                .get(commit)
+                .map(|info| self.theme.age_color(self.now().saturating_sub(info.time))),
+            None => None,
+        };
+        match color {
+            Some(color) => format!("\x1b[{color}m{commit}\x1b[0m"),
+            None => commit.to_string(),
+        }
+    }
+
+    fn hyperlink(&self, text: String, commit: &str) -> String {
+        match (&self.link_url, self.info.get(commit)) {
+            (Some(url), Some(info)) => {
+                let url = url.replace("{commit}", &info.sha);
+                format!("\x1b]8;;{url}\x1b\\{text}\x1b]8;;\x1b\\")
+            }
+            _ => text,
+        }
+    }
+
+    fn lookup_commit(&self) -> Option<String> {
+        if self.start <= self.offset && self.offset < self.start + self.commits.len() as u32 {
+            let commit = &self.commits[(self.offset - self.start) as usize];
+            if !commit.is_empty() {
+                return Some(commit.clone());
+            }
+        }
+        None
+    }
+
+    fn count_hunk_authors(&self) -> usize {
+        self.commits
+            .iter()
+            .filter_map(|commit| self.info.get(commit))
+            .map(|info| &info.author)
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    fn changed_files(&self, commit: &str) -> io::Result<HashSet<String>> {
+        // -z leaves paths unquoted
+        let output = self.run(
+            self.git()
+                .arg("diff-tree")
+                .arg("--root")
+                .arg("--no-commit-id")
+                .arg("--name-only")
+                .arg("-r")
+                .arg("-z")
+                .arg(commit),
+        )?;
+        Ok(output
+            .split('\0')
+            .filter(|path| !path.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn dirty_files(&self, patch: &[u8]) -> io::Result<Vec<String>> {
+        let mut files = Vec::new();
+        for line in patch.lines() {
+            let line = strip_ansi_escapes::strip_str(line?);
+            let path = match (line.strip_prefix("--- "), line.strip_prefix("+++ ")) {
+                (Some(path), _) => Self::parse_path(path, &self.src_prefixes),
+                (_, Some(path)) => Self::parse_path(path, &self.dst_prefixes),
+                _ => None,
+            };
+            if let Some(path) = path.filter(|path| !files.contains(path)) {
+                files.push(path);
+            }
+        }
+        if files.is_empty() {
+            return Ok(files);
+        }
+        let output = self.run(
+            self.git()
+                .arg("--literal-pathspecs")
+                .arg("status")
+                .arg("--porcelain")
+                .arg("--no-renames")
+                .arg("-z")
+                .arg("--")
+                .args(&files),
+        )?;
+        // entries are two status letters and a space before the path, where the output may
+        // have been trimmed of a leading space
+        Ok(output
+            .split('\0')
+            .filter_map(|entry| Some(entry.get(2..)?.trim_start().to_string()))
+            .filter(|path| !path.is_empty())
+            .collect())
+    }
+
+    fn is_skipped_file(&self) -> bool {
+        let skipped_extension = self.file.as_ref().is_some_and(|file| {
+            self.skip_extensions.iter().any(|ext| {
+                file.strip_suffix(ext.as_str())
+                    .is_some_and(|stem| stem.ends_with('.'))
+            })
+        });
+        skipped_extension
+            || match (&self.changed_files, &self.file) {
+                (Some(files), Some(file)) => !files.contains(file),
+                (Some(_), None) => true,
+                (None, _) => false,
+            }
+    }
+
+    fn reset_file(&mut self) {
+        self.file = None;
+        self.new_file = None;
+        self.source = None;
+        self.symlink = false;
+        self.parents = 0;
+        self.blob = None;
+        self.commits.clear();
+        self.hunk_seen = false;
+        self.hunk_left = (0, 0);
+    }
+
+    fn annotate_line(&mut self, mut raw: String) -> io::Result<AnnotatedLine> {
+        let in_hunk = Self::hunk_body(&mut self.hunk_left, &strip_ansi_escapes::strip_str(&raw));
+        if !in_hunk {
+            raw = Self::drop_progress(raw);
+        }
+        let line = strip_ansi_escapes::strip_str(&raw);
+        if let Some(command) = self.command.take() {
+            if Self::non_unified_body(&command, &line) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "only unified diffs are supported; pipe through `git diff -u`",
+                ));
+            }
+        }
+        if !in_hunk && (line.starts_with("--- ") || line.starts_with("@@")) {
+            self.unified = true;
+        }
+        let mut old_line = None;
+        let new_line = self.new_line;
+        let (kind, commit, prefix) = if Some(&line) == self.separator.as_ref() {
+            self.reset_file();
+            self.records.push(std::mem::take(&mut self.candidates));
+            (LineKind::Header, None, None)
+        } else if let Some(path) = line.strip_prefix("--- ").filter(|_| !in_hunk) {
+            // without "diff --git" lines, such as from `diff -u`, the next file starts here
+            if self.hunk_seen {
+                self.reset_file();
+            }
+            if !self.post_apply {
+                self.file = Self::parse_path(path, &self.src_prefixes);
+            }
+            (LineKind::Header, None, None)
+        } else if let Some(path) = line.strip_prefix("+++ ").filter(|_| !in_hunk) {
+            self.new_file = Self::parse_path(path, &self.dst_prefixes);
+            if self.post_apply {
+                self.file.clone_from(&self.new_file);
+            }
+            (LineKind::Header, None, None)
+        } else if let Some(parents) = Self::combined_parents(&line) {
+            // combined diffs of merges have a column per parent, these hunks are not blamed
+            self.parents = parents;
+            self.commits.clear();
+            self.maxlen = self.maxlen.max(self.abbrev);
+            self.widths = self.column_widths();
+            self.hunk_seen = true;
+            (LineKind::Header, None, None)
+        } else if let Some(columns) = line
+            .get(..self.parents)
+            .filter(|columns| columns.chars().all(|c| matches!(c, ' ' | '-' | '+')))
+            .filter(|_| self.parents > 0)
+        {
+            let kind = if columns.contains('-') {
+                LineKind::Removed
+            } else if columns.contains('+') {
+                LineKind::Added
+            } else {
+                LineKind::Context
+            };
+            // only lines new to all parents are known to come from the merge itself
+            let marker = if columns.chars().all(|c| c == '+') {
+                self.added_marker
+            } else {
+                self.unknown_marker
+            };
+            (kind, None, Some(self.marker_prefix(marker)))
+        } else if line.starts_with("@@ ") {
+            self.parents = 0;
+            if self.file.is_some() && !self.is_skipped_file() {
+                self.blame_hunk(&line)?;
+                if self.hunk_authors {
+                    match self.count_hunk_authors() {
+                        1 => raw.push_str(" (1 author)"),
+                        n => raw.push_str(&format!(" ({n} authors)")),
+                    }
+                }
+                if self.header_context && !self.symlink {
+                    if let Some(commit) = self.blame_header_context(&line)? {
+                        raw.push_str(&format!(" ({commit})"));
+                    }
+                }
+            } else {
+                self.commits.clear();
+            }
+            self.new_line = Self::parse_new_start(&line);
+            // align the numbers of a hunk to its last line
+            let counts = Self::hunk_counts(&line).unwrap_or_default();
+            self.ref_width = (self.new_line + counts.1.saturating_sub(1))
+                .to_string()
+                .len();
+            self.hunk_left = counts;
+            self.hunk_seen = true;
+            (LineKind::Header, None, None)
+        } else if line.starts_with("diff ") {
+            self.reset_file();
+            (LineKind::Header, None, None)
+        } else if [
+            "old mode ",
+            "new mode ",
+            "new file mode ",
+            "deleted file mode ",
+        ]
+        .iter()
+        .any(|header| line.strip_prefix(header) == Some("120000"))
+        {
+            self.symlink = true;
+            (LineKind::Header, None, None)
+        } else if let Some(index) = line.strip_prefix("index ") {
+            // index 6d0a9487a999..5aa46cc774fb 100644
+            self.symlink |= index.split_whitespace().nth(1) == Some("120000");
+            self.blob = index
+                .split_whitespace()
+                .next()
+                .and_then(|blobs| blobs.split("..").nth(1))
+                .map(str::to_string);
+            (LineKind::Header, None, None)
+        } else if self.hunk_seen && self.is_skipped_file() {
+            let kind = match line.chars().next() {
+                Some(' ') => LineKind::Context,
+                Some('-') => LineKind::Removed,
+                Some('+') => LineKind::Added,
+                _ => LineKind::Header,
+            };
+            (kind, None, None)
+        } else if self.hunk_seen && line.starts_with('\\') {
+            // "\ No newline at end of file" belongs to the line before, neither side has it
+            let prefix = self.marker_prefix(' ');
+            (LineKind::Header, None, Some(prefix))
+        } else if self.post_apply && line.starts_with('-') {
+            // removed lines are gone after applying, and have no new side to blame
+            let prefix = self.marker_prefix('-');
+            (LineKind::Removed, None, Some(prefix))
+        } else if line.starts_with(' ')
+            || line.starts_with('-')
+            || (self.post_apply && line.starts_with('+'))
+        {
+            let kind = match line.chars().next() {
+                Some(' ') => LineKind::Context,
+                Some('-') => LineKind::Removed,
+                _ => LineKind::Added,
+            };
+            let commit = self.lookup_commit();
+            if !self.post_apply {
+                old_line = Some(self.offset);
+            }
+            self.offset += 1;
+            if commit.is_some() {
+                self.blamed_lines += 1;
+            }
+            let prefix = match &commit {
+                Some(commit) if commit.starts_with('^') || commit.chars().all(|c| c == '0') => {
+                    if !commit.starts_with('^') {
+                        self.uncommitted += 1;
+                    }
+                    self.marker_prefix(self.ancestor_marker)
+                }
+                Some(commit)
+                    if self
+                        .applied
+                        .as_ref()
+                        .is_some_and(|applied| applied.starts_with(commit.as_str())) =>
+                {
+                    self.commit_prefix(commit)
+                }
+                Some(commit) => {
+                    *self.candidates.entry(commit.clone()).or_default() += 1;
+                    if let (Some(report), Some(file), LineKind::Removed) =
+                        (&mut self.file_report, &self.file, kind)
+                    {
+                        match report.last_mut() {
+                            Some((last, counts)) if last == file => {
+                                *counts.entry(commit.clone()).or_default() += 1
+                            }
+                            _ => report.push((file.clone(), HashMap::from([(commit.clone(), 1)]))),
+                        }
+                    }
+                    if let (Some(notes), Some(blob), LineKind::Context) =
+                        (&mut self.notes, &self.blob, kind)
+                    {
+                        notes.push(format!("{} {} {}", blob, self.new_line, commit));
+                    }
+                    let mut prefix = self.commit_prefix(commit);
+                    if self.first_only && !self.shown.insert(commit.clone()) {
+                        let width = strip_ansi_escapes::strip_str(&prefix).chars().count();
+                        prefix = format!("{:width$}", "");
+                    }
+                    if kind == LineKind::Removed && self.is_old(commit) {
+                        self.old_lines += 1;
+                        if !self.old_commits.contains(commit) {
+                            self.old_commits.push(commit.clone());
+                        }
+                        // flag it in place of the separating space
+                        format!("{}!", prefix.strip_suffix(' ').unwrap_or(&prefix))
+                    } else {
+                        prefix
+                    }
+                }
+                None => self.marker_prefix(self.unknown_marker),
+            };
+            if kind != LineKind::Removed {
+                self.new_line += 1;
+            }
+            let prefix = match (kind, self.context_column) {
+                (LineKind::Context, ContextColumn::Changed) => Some(format!(
+                    "{:width$}",
+                    "",
+                    width = strip_ansi_escapes::strip_str(&prefix).chars().count()
+                )),
+                (LineKind::Context, ContextColumn::Never) => None,
+                _ => Some(prefix),
+            };
+            (kind, commit, prefix)
+        } else if line.starts_with('+') {
+            self.new_line += 1;
+            let prefix = self.marker_prefix(self.added_marker);
+            (LineKind::Added, None, Some(prefix))
+        } else if let Some((meta, paths)) = raw.strip_prefix(':').and_then(|l| l.split_once('\t')) {
+            // raw diff-tree output, such as ":100644 100644 bcd1234 0123456 M\tfile"; as
+            // stripping escapes drops tabs, parse the raw line
+            let status = meta.split_whitespace().last().unwrap_or("");
+            self.raw
+                .push((status.to_string(), paths.replace('\t', " -> ")));
+            (LineKind::Header, None, None)
+        } else if !self.unified && Self::is_non_unified(&line) {
+            // text such as a commit message may look alike, the next line tells
+            self.command = Some(line.clone());
+            (LineKind::Header, None, None)
+        } else {
+            (LineKind::Header, None, None)
+        };
+        let prefix = match prefix {
+            Some(prefix) if self.ref_numbers => Some(match kind {
+                LineKind::Context | LineKind::Added => {
+                    format!("{new_line:>width$} {prefix}", width = self.ref_width)
+                }
+                _ => format!("{:width$} {prefix}", "", width = self.ref_width),
+            }),
+            prefix => prefix,
+        };
+        Ok(AnnotatedLine {
+            line: raw,
+            kind,
+            commit,
+            prefix,
+            crlf: false,
+            file: self.file.clone(),
+            old_line,
+            symlink: self.symlink,
+        })
+    }
+
+    fn hunk_counts(line: &str) -> Result<(u32, u32), &'static str> {
+        let (old, new) = match Self::hunk_ranges(line) {
+            Some([old, new]) => (old.strip_prefix('-'), new.strip_prefix('+')),
+            None => (None, None),
+        };
+        let count = |range: Option<&str>| match range.and_then(Self::parse_range) {
+            Some((_, count)) => Ok(count),
+            None => Err("malformed hunk header"),
+        };
+        Ok((count(old)?, count(new)?))
+    }
+
+    /// Check that a diff can be annotated, without running git.
+    ///
+    /// * `reader` - A reader for the diff to check.
+    /// * `writer` - A writer for diagnostics, one per problem with its line number.
+    ///
+    /// Returns the number of problems found.
+    pub fn parse_only<R: BufRead, W: Write>(reader: R, mut writer: W) -> io::Result<usize> {
+        let mut problems = 0;
+        // old and new side lines left in the current hunk
+        let (mut old, mut new) = (0, 0);
+        let mut number = 0;
+        // whether unified headers were seen, and a line before that looking like a command
+        let mut unified = false;
+        let mut command: Option<(usize, String)> = None;
+        for line in reader.split(b'\n') {
+            let line = line?;
+            let line = strip_ansi_escapes::strip_str(String::from_utf8_lossy(&line));
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            number += 1;
+            if let Some((number, command)) = command.take() {
+                if Self::non_unified_body(&command, line) {
+                    problems += 1;
+                    writeln!(writer, "{number}: not a unified diff: {command}")?;
+                }
+            }
+            let problem = if old > 0 || new > 0 {
+                match line.chars().next() {
+                    Some(' ') | None if old > 0 && new > 0 => {
+                        (old, new) = (old - 1, new - 1);
+                        None
+                    }
+                    Some('-') if old > 0 => {
+                        old -= 1;
+                        None
+                    }
+                    Some('+') if new > 0 => {
+                        new -= 1;
+                        None
+                    }
+                    Some('\\') => None,
+                    _ => {
+                        // resync at the next header instead of reporting every line
+                        (old, new) = (0, 0);
+                        Some("line does not match the hunk header counts")
+                    }
+                }
+            } else if Self::combined_parents(line).is_some() {
+                // the columns of combined hunks are annotated with placeholders only
+                None
+            } else if line.starts_with("@@") {
+                unified = true;
+                match Self::hunk_counts(line) {
+                    Ok(counts) => {
+                        (old, new) = counts;
+                        None
+                    }
+                    Err(problem) => Some(problem),
+                }
+            } else if line.starts_with("--- ") {
+                unified = true;
+                None
+            } else if !unified && Self::is_non_unified(line) {
+                command = Some((number, line.to_string()));
+                None
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                problems += 1;
+                writeln!(writer, "{number}: {problem}: {line}")?;
+            }
+        }
+        if old > 0 || new > 0 {
+            problems += 1;
+            writeln!(
+                writer,
+                "{number}: hunk ends early, {old} old and {new} new lines missing"
+            )?;
+        }
+        Ok(problems)
+    }
+
+    fn non_unified_body(command: &str, line: &str) -> bool {
+        // context diff hunks go on with the old range, normal diff commands with their lines
+        match command.starts_with("***************") {
+            true => line.starts_with("*** "),
+            false => line.starts_with('<') || line.starts_with('>'),
+        }
+    }
+
+    fn is_non_unified(line: &str) -> bool {
+        // context diff hunk separator, or normal diff command such as "2c2" or "5,7d4"
+        if line.starts_with("***************") {
+            return true;
+        }
+        let mut ranges = line.split(['a', 'c', 'd']);
+        match (ranges.next(), ranges.next(), ranges.next()) {
+            (Some(old), Some(new), None) => [old, new].iter().all(|range| {
+                let nums: Vec<_> = range.split(',').collect();
+                nums.len() <= 2
+                    && nums
+                        .iter()
+                        .all(|n| !n.is_empty() && n.bytes().all(|c| c.is_ascii_digit()))
+            }),
+            _ => false,
+        }
+    }
+
+    fn strip_colors(patch: &[u8]) -> Vec<u8> {
+        // strip_ansi_escapes also drops tabs, so only remove CSI sequences such as colors
+        let mut stripped = Vec::with_capacity(patch.len());
+        let mut bytes = patch.iter().copied().peekable();
+        while let Some(b) = bytes.next() {
+            if b == 0x1b && bytes.next_if_eq(&b'[').is_some() {
+                for b in bytes.by_ref() {
+                    if (0x40..=0x7e).contains(&b) {
+                        break;
+                    }
+                }
+            } else {
+                stripped.push(b);
+            }
+        }
+        stripped
+    }
+
+    fn apply_patch(&self, patch: &[u8]) -> io::Result<String> {
+        let index = Self::scratch_file("post-apply.index");
+        let file = Self::scratch_file("post-apply.patch");
+        let result = std::fs::write(&file, Self::strip_colors(patch))
+            .and_then(|_| self.commit_patch(&file, &index));
+        let _ = std::fs::remove_file(&index);
+        let _ = std::fs::remove_file(&file);
+        result
+    }
+
+    fn commit_patch(&self, patch: &Path, index: &Path) -> io::Result<String> {
+        // apply to a scratch index and commit it, leaving the work tree and real index untouched
+        let git = || {
+            let mut cmd = self.git();
+            cmd.env("GIT_INDEX_FILE", index);
+            cmd
+        };
+        self.run(git().arg("read-tree").arg(&self.rev))?;
+        // from a file, a patch written to a pipe could fill up with git reporting back
+        let output = (self.runner)(git().arg("apply").arg("--cached").arg(patch))?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "git apply --cached: {}",
+                Self::stderr_message(&output.stderr)
+            )));
+        }
+        let tree = self.run(git().arg("write-tree"))?;
+        self.run(
+            git()
+                .arg("commit-tree")
+                .arg(tree)
+                .arg("-p")
+                .arg(&self.rev)
+                .arg("-m")
+                .arg("Applied diff")
+                .env("GIT_AUTHOR_NAME", "Not Committed Yet")
+                .env("GIT_AUTHOR_EMAIL", "not.committed.yet")
+                .env("GIT_COMMITTER_NAME", "Not Committed Yet")
+                .env("GIT_COMMITTER_EMAIL", "not.committed.yet"),
+        )
+    }
+
+    fn right(&self) -> Option<usize> {
+        (self.side == Side::Right).then_some(self.side_width)
+    }
+
+    fn filter_diff<R: BufRead, W: Write + Sync + Send>(
+        &mut self,
+        reader: R,
+        writer: W,
+    ) -> io::Result<()> {
+        if self.json {
+            self.json_diff(reader, writer)
+        } else if self.inner.is_some() {
+            self.wrapping_diff(reader, writer)
+        } else {
+            self.simple_diff(reader, writer)
+        }
+    }
+
+    fn wrapping_diff<R: BufRead, W: Write + Sync + Send>(
+        &mut self,
+        reader: R,
+        mut writer: W,
+    ) -> io::Result<()> {
+        if let Some(inner) = &self.inner {
+            let cmd = Command::new(&inner[0])
+                .args(&inner[1..])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .map_err(|e| io::Error::new(e.kind(), format!("Inner cmd: {}", inner[0])))?;
+
+            let (tx, rx) = mpsc::channel::<(Option<String>, Option<String>, bool)>();
+            let mut folder = ContextFolder::new(self.fold, self.wrap, self.right());
+            let stdout = BufReader::new(cmd.stdout.unwrap());
+            let mut stdin = cmd.stdin.unwrap();
+
+            std::thread::scope(|s| {
+                let t: ScopedJoinHandle<io::Result<()>> = s.spawn(move || {
+                    for line in stdout.lines() {
+                        let (pfx, context, crlf) = rx.recv().map_err(io::Error::other)?;
+                        // restore the line terminator of the input, whatever the filter emits
+                        let mut line = line?;
+                        if crlf {
+                            line.push('\r');
+                        }
+                        folder.write(&mut writer, pfx, context, &line)?;
+                    }
+                    folder.flush(&mut writer)
+                });
+                for annotated in self.annotate_lines(reader) {
+                    let annotated = annotated?;
+                    let context = annotated.context_commit();
+                    tx.send((annotated.prefix, context, annotated.crlf))
+                        .map_err(io::Error::other)?;
+                    writeln!(stdin, "{}", annotated.line)?;
+                }
+                drop(stdin);
+                t.join().unwrap()
+            })?;
+        }
+        Ok(())
+    }
+
+    fn simple_diff<R: BufRead, W: Write + Sync + Send>(
+        &mut self,
+        reader: R,
+        mut writer: W,
+    ) -> io::Result<()> {
+        let mut folder = ContextFolder::new(self.fold, self.wrap, self.right());
+        for annotated in self.annotate_lines(reader) {
+            let mut annotated = annotated?;
+            let context = annotated.context_commit();
+            if annotated.crlf {
+                annotated.line.push('\r');
+            }
+            folder.write(&mut writer, annotated.prefix, context, &annotated.line)?;
+        }
+        folder.flush(&mut writer)
+    }
+
+    fn json_string(text: &str) -> String {
+        let mut quoted = String::with_capacity(text.len() + 2);
+        quoted.push('"');
+        for c in text.chars() {
+            match c {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                '\n' => quoted.push_str("\\n"),
+                '\r' => quoted.push_str("\\r"),
+                '\t' => quoted.push_str("\\t"),
+                c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+                c => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
+
+    fn json_line(file: Option<&str>, annotated: &AnnotatedLine) -> String {
+        let kind = match annotated.kind {
+            LineKind::Header => "header",
+            LineKind::Context => "context",
+            LineKind::Removed => "removed",
+            LineKind::Added => "added",
+        };
+        let mut text =
+            String::from_utf8_lossy(&Self::strip_colors(annotated.line.as_bytes())).into_owned();
+        if annotated.crlf {
+            text.push('\r');
+        }
+        format!(
+            "{{\"file\":{},\"old_line\":{},\"kind\":\"{}\",\"commit\":{},\"text\":{}}}",
+            file.map_or("null".to_string(), Self::json_string),
+            annotated
+                .old_line
+                .map_or("null".to_string(), |n| n.to_string()),
+            kind,
+            annotated
+                .commit
+                .as_deref()
+                .map_or("null".to_string(), Self::json_string),
+            Self::json_string(&text)
+        )
+    }
+
+    fn json_diff<R: BufRead, W: Write>(&mut self, reader: R, mut writer: W) -> io::Result<()> {
+        // group lines by file, starting a new one at each "diff" or second "---" line
+        let mut files: Vec<(Option<String>, Vec<AnnotatedLine>, bool)> = Vec::new();
+        for annotated in self.annotate_lines(reader) {
+            let annotated = annotated?;
+            let plain = strip_ansi_escapes::strip_str(&annotated.line);
+            let old_header = plain.starts_with("--- ") && annotated.kind == LineKind::Header;
+            let seen = files.last().map(|(_, _, seen)| *seen);
+            if seen.is_none() || plain.starts_with("diff ") || old_header && seen == Some(true) {
+                files.push((None, Vec::new(), false));
+            }
+            let (path, lines, seen) = files.last_mut().unwrap();
+            *seen |= old_header;
+            if annotated.file.is_some() {
+                path.clone_from(&annotated.file);
+            }
+            lines.push(annotated);
+        }
+        // lines name the file of their group, including the headers before its path
+        let files: Vec<_> = files
+            .iter()
+            .map(|(path, lines, _)| {
+                let lines: Vec<_> = lines
+                    .iter()
+                    .map(|annotated| Self::json_line(path.as_deref(), annotated))
+                    .collect();
+                format!(
+                    "{{\"path\":{},\"lines\":[{}]}}",
+                    path.as_deref()
+                        .map_or("null".to_string(), Self::json_string),
+                    lines.join(",")
+                )
+            })
+            .collect();
+        let mut candidates: Vec<_> = self
+            .all_candidates()
+            .into_iter()
+            .map(|commit| Self::json_string(commit))
+            .collect();
+        candidates.sort();
+        writeln!(
+            writer,
+            "{{\"version\":2,\"files\":[{}],\"candidates\":[{}]}}",
+            files.join(","),
+            candidates.join(",")
+        )
+    }
+
+    fn format_candidates(
+        &self,
+        counts: &HashMap<String, usize>,
+        format: &str,
+        rank: &HashMap<&str, usize>,
+    ) -> io::Result<Vec<String>> {
+        let mut candidates: Vec<_> = counts.keys().cloned().collect();
+        if candidates.is_empty() {
+            // git show would fall back to HEAD
+            return Ok(Vec::new());
+        }
+        if self.squash {
+            let subjects = self.run(
+                self.git()
+                    .arg("show")
+                    .arg("-s")
+                    .arg("--format=%H %s")
+                    .args(&candidates),
+            )?;
+            candidates = Self::squash_candidates(&subjects);
+        }
+        let output = self.run(
+            self.git()
+                .arg("show")
+                .arg("-s")
+                .arg(match self.candidate_color {
+                    true => "--color=always",
+                    false => "--color=never",
+                })
+                .arg(format!("--abbrev={}", self.abbrev))
+                .arg(format!("--format=%at %H {}", format))
+                .args(&candidates),
+        )?;
+        let frequency;
+        let rank = match self.candidate_sort {
+            CandidateSort::Frequency => {
+                frequency = Self::frequency_rank(&output, counts);
+                &frequency
+            }
+            _ => rank,
+        };
+        Ok(Self::sort_candidates(&output, rank)
+            .into_iter()
+            .map(|(time, line)| match self.candidate_date {
+                Some(date) => format!("{} {line}", self.format_candidate_date(date, time)),
+                None => line,
+            })
+            .collect())
+    }
+
+    fn format_candidate_date(&self, date: CandidateDate, time: u64) -> String {
+        match date {
+            CandidateDate::Iso => {
+                let seconds = time % (24 * 60 * 60);
+                format!(
+                    "{} {:02}:{:02}:{:02} +0000",
+                    Self::format_date(time),
+                    seconds / 3600,
+                    seconds / 60 % 60,
+                    seconds % 60
+                )
+            }
+            CandidateDate::Short => Self::format_date(time),
+            CandidateDate::Relative => self.relative_date(time),
+        }
+    }
+
+    fn frequency_rank<'a>(
+        output: &'a str,
+        counts: &HashMap<String, usize>,
+    ) -> HashMap<&'a str, usize> {
+        output
+            .lines()
+            .filter_map(|line| line.split(' ').nth(1))
+            .map(|sha| {
+                let lines: usize = counts
+                    .iter()
+                    .filter(|(commit, _)| sha.starts_with(commit.as_str()))
+                    .map(|(_, lines)| lines)
+                    .sum();
+                // ranks sort ascending, so the most lines rank first
+                (sha, usize::MAX - lines)
+            })
+            .collect()
+    }
+
+    fn sort_candidates(output: &str, rank: &HashMap<&str, usize>) -> Vec<(u64, String)> {
+        // each line is prefixed with "%at %H ", use the sha as tiebreaker for a stable order
+        let mut lines: Vec<_> = output
+            .lines()
+            .map(|line| {
+                let mut parts = line.splitn(3, ' ');
+                let time = parts.next().unwrap_or("0").parse::<u64>().unwrap_or(0);
+                let sha = parts.next().unwrap_or("");
+                let rank = rank.get(sha).copied().unwrap_or(usize::MAX);
+                // keep the remainder verbatim to preserve whitespace of the user format
+                (rank, time, sha, parts.next().unwrap_or(""))
+            })
+            .collect();
+        lines.sort();
+        lines
+            .into_iter()
+            .map(|(_, time, _, line)| (time, line.to_string()))
+            .collect()
+    }
+
+    fn limit_candidates(mut lines: Vec<String>, max: Option<usize>) -> Vec<String> {
+        match max {
+            Some(max) if lines.len() > max => {
+                let more = lines.len() - max;
+                lines.truncate(max);
+                lines.push(format!("… and {more} more"));
+                lines
+            }
+            _ => lines,
+        }
+    }
+
+    fn squash_candidates(subjects: &str) -> Vec<String> {
+        // each line is "%H %s", drop fixup!/squash! commits referencing another candidate
+        let commits: Vec<_> = subjects
+            .lines()
+            .map(|line| line.split_once(' ').unwrap_or((line, "")))
+            .collect();
+        let targets: HashSet<_> = commits.iter().map(|(_, subject)| *subject).collect();
+        commits
+            .iter()
+            .filter(|(_, subject)| {
+                let mut subject = *subject;
+                while let Some(target) = subject
+                    .strip_prefix("fixup! ")
+                    .or_else(|| subject.strip_prefix("squash! "))
+                {
+                    if targets.contains(target) {
+                        return false;
+                    }
+                    subject = target;
+                }
+                true
+            })
+            .map(|(sha, _)| sha.to_string())
+            .collect()
+    }
+
+    fn format_raw(status: &str, paths: &str) -> String {
+        let change = match status.trim_start_matches(':').chars().next() {
+            Some('A') => "added",
+            Some('C') => "copied",
+            Some('D') => "deleted",
+            Some('M') => "modified",
+            Some('R') => "renamed",
+            Some('T') => "type changed",
+            Some('U') => "unmerged",
+            _ => "unknown",
+        };
+        format!("{change}: {paths}")
+    }
+
+    fn is_old(&self, commit: &str) -> bool {
+        match (self.max_age, self.info.get(commit)) {
+            (Some(max_age), Some(info)) => self.now().saturating_sub(info.time) > max_age,
+            _ => false,
+        }
+    }
+
+    fn old_lines_warning(&self) -> Option<String> {
+        if self.old_lines == 0 {
+            return None;
+        }
+        Some(format!(
+            "{} changed lines older than {} days, last touched by {}",
+            self.old_lines,
+            self.max_age.unwrap_or(0) / (24 * 60 * 60),
+            self.old_commits.join(", ")
+        ))
+    }
+
+    fn all_candidates(&self) -> HashSet<&String> {
+        // candidates of the records before the current one, a commit may show up in several
+        self.records
+            .iter()
+            .chain([&self.candidates])
+            .flat_map(HashMap::keys)
+            .collect()
+    }
+
+    fn uncommitted_warning(&self) -> Option<String> {
+        // blaming the index, work tree or given contents finds lines not committed yet by design
+        if self.uncommitted == 0 || self.cached || self.worktree || self.contents.is_some() {
+            return None;
+        }
+        Some(format!(
+            "{} lines blamed as not committed yet, the work tree may have changed since the diff \
+             was generated",
+            self.uncommitted
+        ))
+    }
+
+    fn format_file_report(file: &str, counts: &HashMap<String, usize>) -> Vec<String> {
+        let mut counts: Vec<_> = counts.iter().collect();
+        counts.sort_by(|(a, n), (b, m)| m.cmp(n).then(a.cmp(b)));
+        std::iter::once(format!("{file}:"))
+            .chain(
+                counts
+                    .into_iter()
+                    .map(|(commit, n)| format!("{n:>6} {commit}")),
+            )
+            .collect()
+    }
+
+    fn format_summary(format: &str, lines: usize, commits: usize, files: usize) -> String {
+        format
+            .replace("{lines}", &lines.to_string())
+            .replace("{commits}", &commits.to_string())
+            .replace("{files}", &files.to_string())
+    }
+
+    fn format_coverage(file: &str, total: u32, hunks: &[(u32, u32)]) -> String {
+        // hunks are given as start and exclusive end of the old side lines
+        let mut untouched = Vec::new();
+        let mut line = 1;
+        for &(start, end) in hunks {
+            if start > line {
+                untouched.push((line, start - 1));
+            }
+            line = line.max(end);
+        }
+        if line <= total {
+            untouched.push((line, total));
+        }
+        let outside: u32 = untouched.iter().map(|(start, end)| end - start + 1).sum();
+        let touched = total - outside;
+        let ranges = untouched
+            .iter()
+            .map(|&(start, end)| {
+                if start == end {
+                    start.to_string()
+                } else {
+                    format!("{start}-{end}")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!(
+            "{file}: {touched}/{total} lines touched ({}%), untouched: {ranges}",
+            (touched * 100).checked_div(total).unwrap_or(100)
+        )
+    }
+
+    /// Annotate all lines of a diff.
+    ///
+    /// * `reader` - A reader for the diff to annotate.
+    ///
+    /// Returns each line of the diff with its annotation, for rendering them elsewhere.
+    pub fn blame_lines<R: BufRead>(&mut self, reader: R) -> io::Result<Vec<AnnotatedLine>> {
+        let reader = self.prepare(reader)?;
+        self.annotate_lines(reader).collect()
+    }
+
+    fn prepare<'r, R: BufRead + 'r>(&mut self, mut reader: R) -> io::Result<Box<dyn BufRead + 'r>> {
+        // some Windows tools prefix the stream with a UTF-8 BOM
+        if reader.fill_buf()?.starts_with(b"\xEF\xBB\xBF") {
+            reader.consume(3);
+        }
+        if let Some(commit) = &self.changed_by {
+            self.changed_files = Some(self.changed_files(commit)?);
+        }
+        self.load_cache()?;
+        if !self.post_apply && !self.batch && !self.require_clean {
+            return Ok(Box::new(reader));
+        }
+        // these need the whole diff before annotating its first line
+        let mut patch = Vec::new();
+        reader.read_to_end(&mut patch)?;
+        if self.require_clean {
+            let dirty = self.dirty_files(&patch)?;
+            if !dirty.is_empty() {
+                return Err(io::Error::other(format!(
+                    "uncommitted changes to {}, commit or stash them first",
+                    dirty.join(", ")
+                )));
+            }
+        }
+        if self.post_apply {
+            let applied = self.apply_patch(&patch)?;
+            self.rev = applied.clone();
+            self.applied = Some(applied);
+        }
+        if self.batch {
+            self.prefetch_blames(&patch)?;
+        }
+        Ok(Box::new(io::Cursor::new(patch)))
+    }
+
+    /// Lazily annotate the lines of a diff.
+    ///
+    /// * `reader` - A reader for the diff to annotate.
+    ///
+    /// Returns an iterator yielding each line of the diff with its annotation. Candidate commits
+    /// are collected while iterating.
+    pub fn annotations<'a, R: BufRead + 'a>(
+        &'a mut self,
+        reader: R,
+    ) -> impl Iterator<Item = io::Result<AnnotatedLine>> + 'a {
+        // a failure to prepare is the only item
+        let (reader, error): (Box<dyn BufRead + 'a>, _) = match self.prepare(reader) {
+            Ok(reader) => (reader, None),
+            Err(e) => (Box::new(io::empty()), Some(e)),
+        };
+        error
+            .map(Err)
+            .into_iter()
+            .chain(self.annotate_lines(reader))
+    }
+
+    fn annotate_lines<'a, R: BufRead + 'a>(
+        &'a mut self,
+        reader: R,
+    ) -> impl Iterator<Item = io::Result<AnnotatedLine>> + 'a {
+        let squash = self.squash_whitespace;
+        // split on LF only, to tell CRLF terminated lines
+        let mut lines = reader.split(b'\n');
+        // with squashing, hunks are buffered until their end to inspect all their lines
+        let mut hunk: Option<Vec<AnnotatedLine>> = None;
+        let mut queue = VecDeque::new();
+        let mut done = false;
+        std::iter::from_fn(move || loop {
+            if let Some(annotated) = queue.pop_front() {
+                return Some(Ok(annotated));
+            }
+            if done {
+                return None;
+            }
+            let annotated = match lines.next().map(|line| self.annotate_bytes(line?)) {
+                Some(Ok(annotated)) if squash => annotated,
+                Some(result) => return Some(result),
+                None => {
+                    done = true;
+                    queue.extend(hunk.take().map(Self::squash_whitespace).unwrap_or_default());
+                    // all lines are blamed, so is whatever the cache is to keep
+                    if let Err(e) = self.save_cache() {
+                        return Some(Err(e));
+                    }
+                    continue;
+                }
+            };
+            match &mut hunk {
+                Some(lines) if annotated.kind != LineKind::Header => lines.push(annotated),
+                _ => {
+                    queue.extend(hunk.take().map(Self::squash_whitespace).unwrap_or_default());
+                    if strip_ansi_escapes::strip_str(&annotated.line).starts_with("@@ ") {
+                        hunk = Some(vec![annotated]);
+                    } else {
+                        queue.push_back(annotated);
+                    }
+                }
+            }
+        })
+    }
+
+    fn annotate_bytes(&mut self, line: Vec<u8>) -> io::Result<AnnotatedLine> {
+        let mut line =
+            String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let crlf = line.ends_with('\r');
+        if crlf {
+            line.pop();
+        }
+        let mut annotated = self.annotate_line(line)?;
+        annotated.crlf = crlf;
+        Ok(annotated)
+    }
+
+    fn hunk_body(left: &mut (u32, u32), line: &str) -> bool {
+        // count down the old and new lines of the open hunk, its lines are content as is
+        let (old, new) = left;
+        match line.chars().next() {
+            _ if (*old, *new) == (0, 0) => return false,
+            Some(' ') | None => {
+                *old = old.saturating_sub(1);
+                *new = new.saturating_sub(1);
+            }
+            Some('-') => *old = old.saturating_sub(1),
+            Some('+') => *new = new.saturating_sub(1),
+            Some('\\') => (),
+            Some(_) => return false,
+        }
+        true
+    }
+
+    fn drop_progress(mut line: String) -> String {
+        // tools may inject "\r" terminated progress, keep what follows the last of these
+        let body = line.strip_suffix('\r').unwrap_or(&line);
+        if let Some(pos) = body.rfind('\r') {
+            line.drain(..=pos);
+        }
+        line
+    }
+
+    fn squash_whitespace(hunk: Vec<AnnotatedLine>) -> Vec<AnnotatedLine> {
+        // link targets are paths, where whitespace is significant
+        if hunk.iter().any(|annotated| annotated.symlink) {
+            return hunk;
+        }
+        // the hunk starts with its header, compare the trimmed content of -/+ lines
+        let content = |kind| {
+            hunk.iter()
+                .filter(move |annotated| annotated.kind == kind)
+                .map(|annotated| {
+                    let line = strip_ansi_escapes::strip_str(&annotated.line);
+                    line[1..].trim().to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+        let removed = content(LineKind::Removed);
+        if removed.is_empty() || removed != content(LineKind::Added) {
+            return hunk;
+        }
+        let mut commits: Vec<&str> = Vec::new();
+        for annotated in &hunk {
+            if let (LineKind::Removed, Some(commit)) = (annotated.kind, &annotated.commit) {
+                if !commits.contains(&commit.as_str()) {
+                    commits.push(commit);
+                }
+            }
+        }
+        let width = hunk
+            .iter()
+            .find_map(|annotated| annotated.prefix.as_deref())
+            .map_or(0, |prefix| {
+                strip_ansi_escapes::strip_str(prefix).chars().count()
+            });
+        let commits = if commits.is_empty() {
+            "?".to_string()
+        } else {
+            commits.join(", ")
+        };
+        let summary = AnnotatedLine {
+            line: format!("… whitespace-only change, blamed to {commits} …"),
+            kind: LineKind::Header,
+            commit: None,
+            prefix: Some(format!("{:width$}", "")),
+            crlf: false,
+            file: hunk[0].file.clone(),
+            old_line: None,
+            symlink: false,
+        };
+        let mut hunk = hunk;
+        hunk.truncate(1);
+        hunk.push(summary);
+        hunk
+    }
+
+    /// Annotate a diff with the commit-id that last touched each line.
+    ///
+    /// * `reader` - A reader for the diff to annotate.
+    /// * `writer` - A writer for the annotated diff.
+    pub fn annotate_diff<R: BufRead, W: Write + Sync + Send, CW: Write>(
+        &mut self,
+        reader: R,
+        mut writer: W,
+        mut cand_writer: CW,
+    ) -> io::Result<()> {
+        let reader = self.prepare(reader)?;
+        if self.count_only {
+            self.filter_diff(reader, io::sink())?;
+            return writeln!(writer, "{}", self.all_candidates().len());
+        }
+        self.filter_diff(reader, writer)?;
+        if let Some(format) = &self.format {
+            let history = match self.candidate_sort {
+                CandidateSort::Date | CandidateSort::Frequency => String::new(),
+                CandidateSort::TopoFirstParent => self.run(
+                    self.git()
+                        .arg("rev-list")
+                        .arg("--first-parent")
+                        .arg("--reverse")
+                        .arg(&self.rev),
+                )?,
+            };
+            let rank = history.lines().zip(0..).collect();
+            // with record separators, list the candidates of each record on its own
+            for (i, counts) in self.records.iter().chain([&self.candidates]).enumerate() {
+                if let (1.., Some(separator)) = (i, &self.separator) {
+                    writeln!(cand_writer, "{separator}")?;
+                }
+                let lines = self.format_candidates(counts, format, &rank)?;
+                for line in Self::limit_candidates(lines, self.max_candidates) {
+                    writeln!(cand_writer, "{}", line)?;
+                }
+            }
+        }
+        if let (Some(file), Some(notes)) = (&self.notes_file, &self.notes) {
+            let mut file = io::BufWriter::new(std::fs::File::create(file)?);
+            for note in notes {
+                writeln!(file, "{}", note)?;
+            }
+            file.flush()?;
+        }
+        for (file, counts) in self.file_report.iter().flatten() {
+            for line in Self::format_file_report(file, counts) {
+                writeln!(cand_writer, "{line}")?;
+            }
+        }
+        for (status, paths) in &self.raw {
+            writeln!(cand_writer, "{}", Self::format_raw(status, paths))?;
+        }
+        if self.stat {
+            let format = self.summary_format.as_deref();
+            writeln!(
+                cand_writer,
+                "{}",
+                Self::format_summary(
+                    format.unwrap_or(Self::SUMMARY_FORMAT),
+                    self.blamed_lines,
+                    self.all_candidates().len(),
+                    self.blamed_files.len()
+                )
+            )?;
+        }
+        if self.verify || self.debug {
+            let level = if self.verify { "warning" } else { "debug" };
+            for warning in &self.warnings {
+                writeln!(cand_writer, "{level}: {warning}")?;
+            }
+        }
+        if let Some(warning) = self.uncommitted_warning() {
+            writeln!(cand_writer, "warning: {warning}")?;
+        }
+        if let Some(warning) = self.old_lines_warning() {
+            writeln!(cand_writer, "warning: {warning}")?;
+        }
+        if let Some(coverage) = &self.coverage {
+            for (file, hunks) in coverage {
+                let total = self.line_count(file)?;
+                writeln!(cand_writer, "{}", Self::format_coverage(file, total, hunks))?;
+            }
+        }
+        Ok(())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Cursor;
-    const PATCH: &str = r"diff --git a/tests/bar.txt b/tests/bar.txt
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    const PATCH: &str = r"diff --git a/tests/bar.txt b/tests/bar.txt
+index 6d0a9487a999..5aa46cc774fb 10064
+--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,10 +1,10 @@
+-bar
++barbara
+ 0.5
+ 1
+ 2
+ 3
+ foobar
+ bar ba baz
+-a
+-b
++A
++B
+ C
+diff --git a/tests/foo.txt b/tests/foo.txt
+index 06259808ba40..482e77c74da8 100644
+--- a/tests/foo.txt
++++ b/tests/foo.txt
+@@ -1,5 +1,5 @@
+ foo
+-bar
++baz
+ a
+ b
+ c
+@@ -7,7 +7,7 @@ d
+ +
+ -
+ +++
+-extra
++wtextra
+ bla
+ ---
+ @@ foo
+@@ -17,7 +17,7 @@ bar
+ 3
+ 4
+ 5
+-6
++5z
+ 6a
+ 7
+ 8
+@@ -25,4 +25,3 @@ bar
+ 10
+ 11
+ 12
+-13
+";
+
+    #[test]
+    fn test_parse_hunk() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        let line = "@@ -36,7 +36,7 @@";
+        let end = annotator.parse_hunk(line).unwrap();
+        assert_eq!(annotator.start, 36);
+        assert_eq!(end, 43);
+    }
+
+    #[test]
+    fn test_parse_hunk_without_count() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        assert_eq!(annotator.parse_hunk("@@ -36 +36 @@").unwrap(), 37);
+        assert_eq!(annotator.start, 36);
+        assert_eq!(annotator.parse_hunk("@@ -36 +36,2 @@").unwrap(), 37);
+        assert_eq!(annotator.start, 36);
+        assert_eq!(DiffAnnotator::hunk_counts("@@ -36 +36,2 @@"), Ok((1, 2)));
+        annotator.post_apply = true;
+        assert_eq!(annotator.parse_hunk("@@ -36 +38,2 @@").unwrap(), 40);
+        assert_eq!(annotator.start, 38);
+    }
+
+    #[test]
+    fn test_parse_hunk_without_context_space() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        for line in ["@@ -36,7 +38,7 @@fn foo()", "@@ -36,7 +38,7@@ fn foo()"] {
+            assert_eq!(annotator.parse_hunk(line).unwrap(), 43);
+            assert_eq!(annotator.start, 36);
+            assert_eq!(DiffAnnotator::parse_new_start(line), 38);
+            assert_eq!(DiffAnnotator::hunk_counts(line), Ok((7, 7)));
+        }
+    }
+
+    #[test]
+    fn test_annotate_combined_diff() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        let diff = "diff --cc tests/bar.txt
+index 6d0a948,0a1b2c3..5aa46cc
+--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@@ -1,3 -1,3 +1,3 @@@
+- bar
+ -baz
+++barbara
+  0.5
++ 1
+@@@@ -1,1 -1,1 -1,1 +1,1 @@@@
+ - bar
++++bar
+";
+        let prefixes: Vec<_> = annotator
+            .annotations(diff.as_bytes())
+            .map(|annotated| annotated.unwrap())
+            .filter(|annotated| annotated.kind != LineKind::Header)
+            .map(|annotated| (annotated.kind, annotated.prefix.unwrap()))
+            .collect();
+        assert_eq!(
+            prefixes,
+            [
+                (LineKind::Removed, "?????? ".to_string()),
+                (LineKind::Removed, "?????? ".to_string()),
+                (LineKind::Added, "++++++ ".to_string()),
+                (LineKind::Context, "?????? ".to_string()),
+                (LineKind::Added, "?????? ".to_string()),
+                (LineKind::Removed, "?????? ".to_string()),
+                (LineKind::Added, "++++++ ".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed_hunk() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        for line in ["@@ garbage", "", "@@ +36,7 @@"] {
+            let err = annotator.parse_hunk(line).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), format!("malformed hunk header: {line:?}"));
+        }
+        annotator.file = Some("tests/bar.txt".to_string());
+        let err = annotator
+            .annotate_line("@@ garbage".to_string())
+            .unwrap_err();
+        assert!(err.to_string().contains("@@ garbage"));
+    }
+
+    fn sorted_lines(output: &str, rank: &HashMap<&str, usize>) -> Vec<String> {
+        DiffAnnotator::sort_candidates(output, rank)
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect()
+    }
+
+    #[test]
+    fn test_sort_candidates() {
+        let output = "1700000000 bbbbbb b second\n\
+                      1600000000 cccccc c first\n\
+                      1700000000 aaaaaa a tied";
+        let expected = vec!["c first", "a tied", "b second"];
+        let rank = HashMap::new();
+        assert_eq!(sorted_lines(output, &rank), expected);
+        let reversed = output.lines().rev().collect::<Vec<_>>().join("\n");
+        assert_eq!(sorted_lines(&reversed, &rank), expected);
+        let times: Vec<_> = DiffAnnotator::sort_candidates(output, &rank)
+            .into_iter()
+            .map(|(time, _)| time)
+            .collect();
+        assert_eq!(times, [1600000000, 1700000000, 1700000000]);
+    }
+
+    #[test]
+    fn test_candidate_date() {
+        const DAY: u64 = 24 * 60 * 60;
+        let now = 1_700_000_000;
+        let annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_now(Some(now));
+        let dates: Vec<_> = [
+            CandidateDate::Iso,
+            CandidateDate::Short,
+            CandidateDate::Relative,
+        ]
+        .into_iter()
+        .map(|date| annotator.format_candidate_date(date, now - 21 * DAY))
+        .collect();
+        assert_eq!(
+            dates,
+            ["2023-10-24 22:13:20 +0000", "2023-10-24", "3 weeks ago"]
+        );
+
+        // the date goes before each line of the user format
+        let candidates = |date| {
+            let mut annotator = DiffAnnotator::new(None, None, None, Some("%s".to_string()))
+                .unwrap()
+                .with_candidate_date(date);
+            let mut cwriter = Vec::new();
+            let result = annotator.annotate_diff(Cursor::new(PATCH), io::sink(), &mut cwriter);
+            assert!(result.is_ok());
+            String::from_utf8(cwriter).unwrap()
+        };
+        let plain = candidates(None);
+        let short = |date: &str| date.len() == 10;
+        let relative = |date: &str| date.ends_with(" ago");
+        for (date, is_date) in [
+            (CandidateDate::Short, &short as &dyn Fn(&str) -> bool),
+            (CandidateDate::Relative, &relative),
+        ] {
+            let dated = candidates(Some(date));
+            assert_eq!(dated.lines().count(), plain.lines().count());
+            for (dated, line) in dated.lines().zip(plain.lines()) {
+                let date = dated.strip_suffix(line).unwrap().strip_suffix(' ').unwrap();
+                assert!(is_date(date), "{dated}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_sort_candidates_first_parent() {
+        let output = "1700000000 bbbbbb b merged\n\
+                      1600000000 cccccc c side\n\
+                      1500000000 aaaaaa a mainline\n\
+                      1800000000 dddddd d base";
+        let rank = HashMap::from([("dddddd", 0), ("aaaaaa", 1), ("bbbbbb", 2)]);
+        assert_eq!(
+            sorted_lines(output, &rank),
+            vec!["d base", "a mainline", "b merged", "c side"]
+        );
+    }
+
+    #[test]
+    fn test_sort_candidates_frequency() {
+        let output = "1500000000 aaaaaa11 a oldest\n\
+                      1600000000 bbbbbb22 b most lines\n\
+                      1700000000 cccccc33 c tied\n\
+                      1800000000 dddddd44 d tied";
+        let counts = HashMap::from([
+            ("aaaaaa".to_string(), 1),
+            ("bbbbbb".to_string(), 5),
+            ("cccccc".to_string(), 2),
+            ("dddddd".to_string(), 2),
+        ]);
+        let rank = DiffAnnotator::frequency_rank(output, &counts);
+        assert_eq!(
+            sorted_lines(output, &rank),
+            vec!["b most lines", "c tied", "d tied", "a oldest"]
+        );
+    }
+
+    #[test]
+    fn test_limit_candidates() {
+        let lines: Vec<_> = ["a", "b", "c", "d", "e"].map(str::to_string).into();
+        assert_eq!(
+            DiffAnnotator::limit_candidates(lines.clone(), Some(2)),
+            vec!["a", "b", "… and 3 more"]
+        );
+        assert_eq!(
+            DiffAnnotator::limit_candidates(lines.clone(), Some(5)),
+            lines
+        );
+        assert_eq!(DiffAnnotator::limit_candidates(lines.clone(), None), lines);
+    }
+
+    #[test]
+    fn test_squash_candidates() {
+        let subjects = "aaaaaa Add feature\n\
+                        bbbbbb fixup! Add feature\n\
+                        cccccc squash! fixup! Add feature\n\
+                        dddddd fixup! Unrelated\n\
+                        eeeeee Fix bug";
+        assert_eq!(
+            DiffAnnotator::squash_candidates(subjects),
+            vec!["aaaaaa", "dddddd", "eeeeee"]
+        );
+    }
+
+    #[test]
+    fn test_color_by() {
+        assert_ne!(
+            Theme::default().hash_color("b40c1d"),
+            Theme::default().hash_color("6ec7db")
+        );
+        assert_ne!(
+            Theme::default().hash_color("John Doe"),
+            Theme::default().hash_color("Jane Doe")
+        );
+        const DAY: u64 = 24 * 60 * 60;
+        let ages = [
+            0,
+            10 * DAY,
+            60 * DAY,
+            200 * DAY,
+            2 * 365 * DAY,
+            10 * 365 * DAY,
+        ];
+        let colors: HashSet<_> = ages
+            .iter()
+            .map(|age| Theme::default().age_color(*age))
+            .collect();
+        assert_eq!(colors.len(), ages.len());
+
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_color_by(Some(ColorBy::Commit));
+        let color = Theme::default().hash_color("b40c1d");
+        assert_eq!(
+            annotator.colorize("b40c1d"),
+            format!("\x1b[{color}mb40c1d\x1b[0m")
+        );
+        annotator.info.insert(
+            "b40c1d".to_string(),
+            CommitInfo {
+                author: "Jane Doe".to_string(),
+                ..Default::default()
+            },
+        );
+        annotator.color_by = Some(ColorBy::Author);
+        let color = Theme::default().hash_color("Jane Doe");
+        assert_eq!(
+            annotator.colorize("b40c1d"),
+            format!("\x1b[{color}mb40c1d\x1b[0m")
+        );
+        annotator.color_by = Some(ColorBy::Age);
+        let color = Theme::default().age_color(u64::MAX);
+        assert_eq!(
+            annotator.colorize("b40c1d"),
+            format!("\x1b[{color}mb40c1d\x1b[0m")
+        );
+    }
+
+    #[test]
+    fn test_color_commits_per_line() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_color_by(Some(ColorBy::Commit));
+        annotator.file = Some("tests/bar.txt".to_string());
+        annotator.commits = ["b40c1d", "6ec7db", "b40c1d", "^b40c1", ""]
+            .map(String::from)
+            .to_vec();
+        annotator.start = 1;
+        annotator.offset = 1;
+        annotator.maxlen = 6;
+        let prefixes = [" bar", " 0.5", "-1", " 2", " 3", " foobar"].map(|line| {
+            annotator
+                .annotate_line(line.to_string())
+                .unwrap()
+                .prefix
+                .unwrap()
+        });
+        let color = Theme::default().hash_color("b40c1d");
+        assert_eq!(prefixes[0], format!("\x1b[{color}mb40c1d\x1b[0m "));
+        assert_eq!(prefixes[0], prefixes[2]);
+        assert_ne!(prefixes[0], prefixes[1]);
+        assert_eq!(prefixes[3..], ["······ ", "?????? ", "?????? "]);
+    }
+
+    #[test]
+    fn test_stable_colors() {
+        let sha = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+        assert_eq!(Theme::Dark.hash_color(sha), Theme::Dark.hash_colors()[7]);
+        assert_eq!(
+            Theme::Solarized.hash_color(sha),
+            Theme::Solarized.hash_colors()[3]
+        );
+
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_color_by(Some(ColorBy::Commit))
+            .with_stable_colors(true);
+        for commit in ["4b825d", "4b825dc6"] {
+            let info = CommitInfo {
+                sha: sha.to_string(),
+                ..Default::default()
+            };
+            annotator.info.insert(commit.to_string(), info);
+        }
+        assert_eq!(annotator.colorize("4b825d"), "\x1b[92m4b825d\x1b[0m");
+        assert_eq!(annotator.colorize("4b825dc6"), "\x1b[92m4b825dc6\x1b[0m");
+    }
+
+    #[test]
+    fn test_theme() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_color_by(Some(ColorBy::Commit));
+        let default = annotator.colorize("b40c1d");
+        for theme in [Theme::Light, Theme::Solarized] {
+            annotator.theme = theme;
+            let color = theme.hash_color("b40c1d");
+            assert_eq!(
+                annotator.colorize("b40c1d"),
+                format!("\x1b[{color}mb40c1d\x1b[0m")
+            );
+            assert_ne!(annotator.colorize("b40c1d"), default);
+        }
+    }
+
+    #[test]
+    fn test_color_by_age_now() {
+        const DAY: u64 = 24 * 60 * 60;
+        let now = 1_700_000_000;
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_color_by(Some(ColorBy::Age))
+            .with_now(Some(now));
+        annotator.info.insert(
+            "b40c1d".to_string(),
+            CommitInfo {
+                time: now - 3 * DAY,
+                ..Default::default()
+            },
+        );
+        annotator.info.insert(
+            "6ec7db".to_string(),
+            CommitInfo {
+                time: now - 100 * DAY,
+                ..Default::default()
+            },
+        );
+        assert_eq!(annotator.colorize("b40c1d"), "\x1b[38;5;196mb40c1d\x1b[0m");
+        assert_eq!(annotator.colorize("6ec7db"), "\x1b[38;5;34m6ec7db\x1b[0m");
+    }
+
+    #[test]
+    fn test_columns() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_columns(vec![Column::Hash, Column::Author, Column::Date]);
+        annotator.commits = ["b40c1d", "6ec7db", "^a1b2c"]
+            .iter()
+            .map(|commit| commit.to_string())
+            .collect();
+        annotator.info.insert(
+            "b40c1d".to_string(),
+            CommitInfo {
+                author: "Jane".to_string(),
+                time: 1_700_000_000,
+                ..Default::default()
+            },
+        );
+        annotator.info.insert(
+            "6ec7db".to_string(),
+            CommitInfo {
+                author: "John Doe".to_string(),
+                time: 951_782_400,
+                ..Default::default()
+            },
+        );
+        annotator.maxlen = 6;
+        annotator.widths = annotator.column_widths();
+        assert_eq!(
+            [
+                annotator.commit_prefix("b40c1d"),
+                annotator.commit_prefix("6ec7db"),
+                annotator.marker_prefix('·'),
+                annotator.marker_prefix('+'),
+            ],
+            [
+                "b40c1d Jane     2023-11-14 ",
+                "6ec7db John Doe 2000-02-29 ",
+                "······ ········ ·········· ",
+                "++++++ ++++++++ ++++++++++ ",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_annotate_markers() {
+        let mut annotator = DiffAnnotator::new(None, Some("b40c1d".to_string()), None, None)
+            .unwrap()
+            .with_markers('.', '!', '*');
+        let reader = Cursor::new(
+            "--- a/tests/bar.txt\n+++ b/tests/bar.txt\n@@ -1,3 +1,3 @@\n-bar\n+barbara\n 0.5\n 1\n\
+             @@ -10,2 +10,2 @@\n C\n-beyond\n+end\n",
+        );
+        let mut writer = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, io::sink());
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            "--- a/tests/bar.txt\n+++ b/tests/bar.txt\n@@ -1,3 +1,3 @@\n\
+             ...... -bar\n****** +barbara\n6ec7db  0.5\n......  1\n\
+             @@ -10,2 +10,2 @@\n6ec7db  C\n!!!!!! -beyond\n****** +end\n"
+        );
+    }
+
+    #[test]
+    fn test_annotate_link_url() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_link_url(Some("https://example.com/commit/{commit}".to_string()));
+        let reader = Cursor::new(
+            "--- a/tests/bar.txt\n+++ b/tests/bar.txt\n@@ -1,2 +1,2 @@\n-bar\n+barbara\n 0.5\n",
+        );
+        let mut writer = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, io::sink());
+        assert!(result.is_ok());
+        let link = |commit: &str| {
+            let sha = rev_parse(commit).unwrap();
+            format!("\x1b]8;;https://example.com/commit/{sha}\x1b\\{commit}\x1b]8;;\x1b\\")
+        };
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            format!(
+                "--- a/tests/bar.txt\n+++ b/tests/bar.txt\n@@ -1,2 +1,2 @@\n{} -bar\n++++++ +barbara\n{}  0.5\n",
+                link("b40c1d"),
+                link("6ec7db")
+            )
+        );
+    }
+
+    #[test]
+    fn test_annotate_show_author() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_columns(vec![Column::Initials]);
+        let author = DiffAnnotator::check_output(
+            &runner(),
+            Command::new("git")
+                .arg("show")
+                .arg("-s")
+                .arg("--format=%an")
+                .arg("b40c1d"),
+        )
+        .unwrap();
+        let initials = DiffAnnotator::initials(&author);
+        assert!(!initials.is_empty());
+        assert_eq!(DiffAnnotator::initials("jane  van doe"), "JVD");
+
+        let reader = Cursor::new(
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -3,2 +3,2 @@
+ 1
+-2
++two
+",
+        );
+        let mut writer = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, io::sink());
+        assert!(result.is_ok());
+        let marker = "+".repeat(initials.chars().count());
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            format!(
+                "--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -3,2 +3,2 @@
+{initials}  1
+{initials} -2
+{marker} +two
+"
+            )
+        );
+    }
+
+    #[test]
+    fn test_show_date() {
+        const DAY: u64 = 24 * 60 * 60;
+        let now = 1_700_000_000;
+        let prefixes = |column| {
+            let mut annotator = DiffAnnotator::new(None, None, None, None)
+                .unwrap()
+                .with_columns(vec![column])
+                .with_now(Some(now));
+            let ages = [("a", 30), ("b", 3 * DAY), ("c", 21 * DAY), ("d", 400 * DAY)];
+            for (commit, age) in ages {
+                let info = CommitInfo {
+                    time: now - age,
+                    ..Default::default()
+                };
+                annotator.info.insert(commit.to_string(), info);
+                annotator.commits.push(commit.to_string());
+            }
+            annotator.widths = annotator.column_widths();
+            let mut prefixes = ages
+                .map(|(commit, _)| annotator.commit_prefix(commit))
+                .to_vec();
+            prefixes.push(annotator.marker_prefix('+'));
+            prefixes
+        };
+        assert_eq!(
+            prefixes(Column::Date),
+            [
+                "2023-11-14 ",
+                "2023-11-11 ",
+                "2023-10-24 ",
+                "2022-10-10 ",
+                "++++++++++ "
+            ]
+        );
+        assert_eq!(
+            prefixes(Column::RelativeDate),
+            [
+                "30 seconds ago ",
+                "3 days ago     ",
+                "3 weeks ago    ",
+                "1 year ago     ",
+                "++++++++++++++ "
+            ]
+        );
+    }
+
+    #[test]
+    fn test_show_subject() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_columns(vec![Column::Subject])
+            .with_subject_width(Some(12));
+        annotator.commits = vec!["b40c1d".to_string(), "6ec7db".to_string()];
+        let info = |subject: &str| CommitInfo {
+            subject: subject.to_string(),
+            ..Default::default()
+        };
+        annotator
+            .info
+            .insert("b40c1d".to_string(), info("tests: Add some test data"));
+        annotator
+            .info
+            .insert("6ec7db".to_string(), info("Fix typo"));
+        annotator.widths = annotator.column_widths();
+        assert_eq!(annotator.commit_prefix("b40c1d"), "tests: Add … ");
+        assert_eq!(annotator.commit_prefix("6ec7db"), "Fix typo     ");
+        assert_eq!(annotator.marker_prefix('+'), "++++++++++++ ");
+    }
+
+    #[test]
+    fn test_show_age() {
+        const DAY: u64 = 24 * 60 * 60;
+        let now = 1_800_000_000;
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_columns(vec![Column::Age])
+            .with_now(Some(now));
+        let ages = [
+            ("a", 2 * 365 + 40),
+            ("b", 95),
+            ("c", 20),
+            ("d", 5),
+            ("e", 0),
+        ];
+        for (commit, days) in ages {
+            let info = CommitInfo {
+                time: now - days * DAY,
+                ..Default::default()
+            };
+            annotator.info.insert(commit.to_string(), info);
+            annotator.commits.push(commit.to_string());
+        }
+        annotator.widths = annotator.column_widths();
+        let prefixes = ages.map(|(commit, _)| annotator.commit_prefix(commit));
+        assert_eq!(prefixes, [" 2y ", " 3M ", " 2w ", " 5d ", " 0d "]);
+        assert_eq!(annotator.marker_prefix('?'), "??? ");
+    }
+
+    #[test]
+    fn test_hunk_authors() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_hunk_authors(true);
+        annotator.commits = ["b40c1d", "6ec7db", "^a1b2c3", "b40c1d", "000000"]
+            .iter()
+            .map(|commit| commit.to_string())
+            .collect();
+        annotator.info.insert(
+            "b40c1d".to_string(),
+            CommitInfo {
+                author: "Jane Doe".to_string(),
+                ..Default::default()
+            },
+        );
+        annotator.info.insert(
+            "6ec7db".to_string(),
+            CommitInfo {
+                author: "John Doe".to_string(),
+                ..Default::default()
+            },
+        );
+        assert_eq!(annotator.count_hunk_authors(), 2);
+    }
+
+    #[test]
+    fn test_blame_header_context() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_blame_header_context(true);
+        let headers: Vec<_> = [
+            "--- a/tests/bar.txt",
+            "@@ -4,2 +4,2 @@ 0.5",
+            "@@ -6,1 +6,1 @@ 3",
+            "@@ -7,1 +7,1 @@ unknown",
+            "@@ -8,1 +8,1 @@",
+        ]
+        .iter()
+        .map(|line| annotator.annotate_line(line.to_string()).unwrap().line)
+        .collect();
+        assert_eq!(
+            headers[1..],
+            [
+                "@@ -4,2 +4,2 @@ 0.5 (6ec7db)",
+                "@@ -6,1 +6,1 @@ 3 (b40c1d)",
+                "@@ -7,1 +7,1 @@ unknown",
+                "@@ -8,1 +8,1 @@",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_annotate_hunk_authors() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_hunk_authors(true);
+
+        let reader = Cursor::new(
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -3,2 +3,2 @@
+ 1
+-2
++two
+",
+        );
+        let mut writer = Vec::new();
+        let mut cwriter = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, &mut cwriter);
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -3,2 +3,2 @@ (1 author)
+b40c1d  1
+b40c1d -2
+++++++ +two
+"
+        );
+    }
+
+    #[test]
+    fn test_annotate_format_whitespace() {
+        let format = "  %h  %s".to_string();
+        let mut annotator = DiffAnnotator::new(None, None, None, Some(format)).unwrap();
+
+        let reader = Cursor::new(PATCH);
+        let mut writer = Vec::new();
+        let mut cwriter = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, &mut cwriter);
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(cwriter).unwrap(),
+            r"  b40c1d  tests: Add some test data
+  6ec7db  tests: Add some changes to test files for blame testing
+"
+        );
+    }
+
+    #[test]
+    fn test_parse_incremental() {
+        let output = "\
+711ac150740b4a4e5aa3fb6af233cd251e339941 2 2 1
+author Jane Doe
+summary tests: Add some changes to test files for blame testing
+filename tests/bar.txt
+711ac150740b4a4e5aa3fb6af233cd251e339941 6 6 2
+filename tests/bar.txt
+ce601b2c293f33dbd21d67a0d8c4c8e1db309983 1 1 1
+author Jane Doe
+summary tests: Add some test data
+boundary
+filename tests/bar.txt
+ce601b2c293f33dbd21d67a0d8c4c8e1db309983 2 3 3
+filename tests/bar.txt
+";
+        let commits = DiffAnnotator::parse_incremental(output, 1, 6);
+        assert_eq!(
+            commits,
+            vec!["^ce601", "711ac1", "^ce601", "^ce601", "^ce601", "711ac1", "711ac1"]
+        );
+        let commits = DiffAnnotator::parse_incremental(output, 5, 6);
+        assert_eq!(commits, vec!["^ce601", "711ac1", "711ac1"]);
+    }
+
+    #[test]
+    fn test_malformed_blame() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        annotator.commits = DiffAnnotator::parse_blame(
+            "b40c1d (Jane Doe 2024-10-12 1) foo\n\n6ec7db (Jane Doe 2024-10-12 3) bar",
+        );
+        annotator.start = 1;
+        annotator.offset = 1;
+        annotator.maxlen = 6;
+        let prefixes: Vec<_> = [" foo", " ", " bar"]
+            .iter()
+            .map(|line| {
+                annotator
+                    .annotate_line(line.to_string())
+                    .unwrap()
+                    .prefix
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(prefixes, vec!["b40c1d ", "?????? ", "6ec7db "]);
+    }
+
+    #[test]
+    fn test_check_output_large_stderr() {
+        let err = DiffAnnotator::check_output(
+            &runner(),
+            Command::new("sh")
+                .arg("-c")
+                .arg("head -c 100000 /dev/zero | tr '\\0' e >&2; exit 1"),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.len() < 4096 + 200);
+        assert!(err.ends_with(&format!("{}... (95904 more bytes)", "e".repeat(10))));
+    }
+
+    #[test]
+    fn test_parse_rename_paths() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        for line in [
+            "diff --git a/tests/bar.txt \"b/tests/b\\303\\244r.txt\"",
+            "similarity index 90%",
+            "rename from tests/bar.txt",
+            "rename to \"tests/b\\303\\244r.txt\"",
+            "--- a/tests/bar.txt",
+            "+++ \"b/tests/b\\303\\244r.txt\"",
+        ] {
+            annotator.annotate_line(line.to_string()).unwrap();
+        }
+        assert_eq!(annotator.file.as_deref(), Some("tests/bar.txt"));
+        assert_eq!(annotator.new_file.as_deref(), Some("tests/b\u{e4}r.txt"));
+        annotator
+            .annotate_line("diff --git a/a b/a".to_string())
+            .unwrap();
+        assert_eq!(annotator.new_file, None);
+    }
+
+    #[test]
+    fn test_parse_only() {
+        let mut diagnostics = Vec::new();
+        let problems = DiffAnnotator::parse_only(Cursor::new(PATCH), &mut diagnostics);
+        assert_eq!(problems.unwrap(), 0);
+        assert!(diagnostics.is_empty());
+
+        let reader = Cursor::new(
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,2 @@
+-bar
++barbara
+*0.5
+@@ -1 +1 @@
+-bar
++barbara
+@@ -x,1 +1,1 @@
+@@@ -1,1 -1,1 +1,1 @@@
+1c1
+@@ -5,2 +5,1 @@
+ a
+",
+        );
+        let problems = DiffAnnotator::parse_only(reader, &mut diagnostics);
+        assert_eq!(problems.unwrap(), 3);
+        assert_eq!(
+            String::from_utf8(diagnostics).unwrap(),
+            r"6: line does not match the hunk header counts: *0.5
+10: malformed hunk header: @@ -x,1 +1,1 @@
+14: hunk ends early, 1 old and 0 new lines missing
+"
+        );
+
+        // commands count only before any unified header, and with their lines
+        let reader = Cursor::new("1a2\n\n1c1\n< bar\n---\n> barbara\n");
+        let mut diagnostics = Vec::new();
+        let problems = DiffAnnotator::parse_only(reader, &mut diagnostics);
+        assert_eq!(problems.unwrap(), 1);
+        assert_eq!(
+            String::from_utf8(diagnostics).unwrap(),
+            "3: not a unified diff: 1c1\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_quoted_paths() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        for (line, path) in [
+            ("--- a/weird name.txt\t", "weird name.txt"),
+            ("--- \"a/weird\\tname.txt\"", "weird\tname.txt"),
+            ("--- \"a/new\\nline.txt\"", "new\nline.txt"),
+            ("--- \"a/b\\303\\244r.txt\"", "b\u{e4}r.txt"),
+        ] {
+            annotator.annotate_line(line.to_string()).unwrap();
+            assert_eq!(annotator.file.as_deref(), Some(path));
+        }
+    }
+
+    #[test]
+    fn test_annotate_no_prefix() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_no_prefix(true);
+        let reader = Cursor::new(
+            "diff --git tests/bar.txt tests/bar.txt\n--- tests/bar.txt\n+++ tests/bar.txt\n@@ -1,2 +1,2 @@\n-bar\n+barbara\n 0.5\n",
+        );
+        let mut writer = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, io::sink());
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            r"diff --git tests/bar.txt tests/bar.txt
+--- tests/bar.txt
++++ tests/bar.txt
+@@ -1,2 +1,2 @@
+b40c1d -bar
+++++++ +barbara
+6ec7db  0.5
+"
+        );
+        for line in ["--- /dev/null", "+++ /dev/null"] {
+            annotator.annotate_line(line.to_string()).unwrap();
+        }
+        assert_eq!(annotator.file, None);
+        assert_eq!(annotator.new_file, None);
+    }
+
+    #[test]
+    fn test_parse_prefixed_paths() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        for (old, new) in [("a/", "b/"), ("i/", "w/"), ("c/", "i/"), ("o/", "w/")] {
+            annotator
+                .annotate_line(format!("--- {old}tests/bar.txt"))
+                .unwrap();
+            annotator
+                .annotate_line(format!("+++ {new}tests/bar.txt"))
+                .unwrap();
+            assert_eq!(annotator.file.as_deref(), Some("tests/bar.txt"));
+            assert_eq!(annotator.new_file.as_deref(), Some("tests/bar.txt"));
+        }
+
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_prefixes(Some("before/".to_string()), Some("after/".to_string()));
+        annotator
+            .annotate_line("--- before/tests/bar.txt".to_string())
+            .unwrap();
+        annotator
+            .annotate_line("+++ after/tests/bar.txt".to_string())
+            .unwrap();
+        assert_eq!(annotator.file.as_deref(), Some("tests/bar.txt"));
+        assert_eq!(annotator.new_file.as_deref(), Some("tests/bar.txt"));
+        annotator
+            .annotate_line("--- a/tests/bar.txt".to_string())
+            .unwrap();
+        assert_eq!(annotator.file, None);
+    }
+
+    #[test]
+    fn test_parse_long_quoted_path() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        let dirs = (0..23).map(|i| format!("dir{i:04}")).collect::<Vec<_>>();
+        let path = format!("{}/f\u{e4}\"l\\e-longs.txt", dirs.join("/"));
+        assert_eq!(path.chars().count(), 200);
+        let quoted = format!("{}/f\\303\\244\\\"l\\\\e-longs.txt", dirs.join("/"));
+        annotator
+            .annotate_line(format!("--- \"a/{quoted}\""))
+            .unwrap();
+        assert_eq!(annotator.file, Some(path));
+    }
+
+    #[test]
+    fn test_annotate_ref_numbers() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_ref_numbers(true);
+        let reader = Cursor::new(
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,3 @@
+-bar
++barbara
++baz
+ 0.5
+@@ -7,3 +8,4 @@
+ bar ba baz
++foo
+ a
+-b
++B
+",
+        );
+        let mut writer = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, io::sink());
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,3 @@
+  b40c1d -bar
+1 ++++++ +barbara
+2 ++++++ +baz
+3 6ec7db  0.5
+@@ -7,3 +8,4 @@
+ 8 6ec7db  bar ba baz
+ 9 ++++++ +foo
+10 b40c1d  a
+   b40c1d -b
+11 ++++++ +B
+"
+        );
+    }
+
+    #[test]
+    fn test_annotate_no_newline_marker() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        let reader = Cursor::new(
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -9,2 +9,2 @@
+ b
+-C
+\ No newline at end of file
++c
+\ No newline at end of file
+",
+        );
+        let mut writer = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, io::sink());
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -9,2 +9,2 @@
+b40c1d  b
+6ec7db -C
+       \ No newline at end of file
+++++++ +c
+       \ No newline at end of file
+"
+        );
+        // the marker takes no line of either side
+        assert_eq!(annotator.offset, 11);
+        assert_eq!(annotator.new_line, 11);
+    }
+
+    #[test]
+    fn test_annotate_empty_hunk() {
+        let diff = r"diff --git a/tests/foo.txt b/tests/foo.txt
+--- a/tests/foo.txt
++++ b/tests/foo.txt
+@@ -3,2 +3,2 @@
+diff --git a/tests/bar.txt b/tests/bar.txt
+--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,2 @@
+-bar
++barbara
+ 0.5
+";
+        for batch in [false, true] {
+            let mut annotator = DiffAnnotator::new(None, None, None, None)
+                .unwrap()
+                .with_batch_blame_ranges(batch);
+            let mut writer = Vec::new();
+            let result = annotator.annotate_diff(Cursor::new(diff), &mut writer, io::sink());
+            assert!(result.is_ok());
+            let output = String::from_utf8(writer).unwrap();
+            // the range blamed for foo.txt is not consumed, bar.txt starts afresh
+            assert_eq!(
+                output.lines().skip(4).collect::<Vec<_>>(),
+                [
+                    "diff --git a/tests/bar.txt b/tests/bar.txt",
+                    "--- a/tests/bar.txt",
+                    "+++ b/tests/bar.txt",
+                    "@@ -1,2 +1,2 @@",
+                    "b40c1d -bar",
+                    "++++++ +barbara",
+                    "6ec7db  0.5",
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_annotate_bom() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+
+        let reader = Cursor::new(
+            "\u{feff}--- a/tests/bar.txt\n+++ b/tests/bar.txt\n@@ -1,2 +1,2 @@\n-bar\n+barbara\n 0.5\n",
+        );
+        let mut writer = Vec::new();
+        let mut cwriter = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, &mut cwriter);
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,2 @@
+b40c1d -bar
+++++++ +barbara
+6ec7db  0.5
+"
+        );
+    }
+
+    #[test]
+    fn test_annotate_raw() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+
+        let raw = ":100644 100644 6d0a948 5aa46cc M\ttests/bar.txt\n\
+                   :000000 100644 0000000 e69de29 A\ttests/baz.txt\n\
+                   :100644 100644 0625980 0625980 R100\ttests/foo.txt\ttests/qux.txt\n";
+        let reader = Cursor::new(raw);
+        let mut writer = Vec::new();
+        let mut cwriter = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, &mut cwriter);
+        assert!(result.is_ok());
+        assert_eq!(String::from_utf8(writer).unwrap(), raw);
+        assert_eq!(
+            String::from_utf8(cwriter).unwrap(),
+            r"modified: tests/bar.txt
+added: tests/baz.txt
+renamed: tests/foo.txt -> tests/qux.txt
+"
+        );
+    }
+
+    #[test]
+    fn test_annotate_normal_diff() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+
+        let reader = Cursor::new("1c1\n< bar\n---\n> barbara\n8,9c8,9\n< a\n< b\n---\n> A\n> B\n");
+        let mut writer = Vec::new();
+        let mut cwriter = Vec::new();
+        let err = annotator
+            .annotate_diff(reader, &mut writer, &mut cwriter)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "only unified diffs are supported; pipe through `git diff -u`"
+        );
+    }
+
+    #[test]
+    fn test_annotate_command_like_text() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+
+        // such as in the message of a patch mail, "1a2" is not a normal diff without its lines
+        let reader = Cursor::new("Fix 1a2\n1a2\n\n--- a/tests/bar.txt\n+++ b/tests/bar.txt\n@@ -1 +1 @@\n-bar\n+barbara\n");
+        let mut writer = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, io::sink());
+        assert!(result.is_ok());
+        assert!(String::from_utf8(writer)
+            .unwrap()
+            .ends_with("b40c1d -bar\n++++++ +barbara\n"));
+    }
+
+    #[test]
+    fn test_annotations() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+
+        let reader = Cursor::new(
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,2 @@
+-bar
++barbara
+ 0.5
+",
+        );
+        let lines: Vec<_> = annotator
+            .annotations(reader)
+            .map(|annotated| {
+                let annotated = annotated.unwrap();
+                (annotated.kind, annotated.commit)
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (LineKind::Header, None),
+                (LineKind::Header, None),
+                (LineKind::Header, None),
+                (LineKind::Removed, Some("b40c1d".to_string())),
+                (LineKind::Added, None),
+                (LineKind::Context, Some("6ec7db".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_annotate_symlink() {
+        let commit = commit_on_head(&[("120000", "link", "old/target")]);
+
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_squash_whitespace_only_hunks(true);
+        annotator.rev = commit.clone();
+        let diff = "diff --git a/link b/link
+index 1de5659..5b8e4b5 120000
+--- a/link
++++ b/link
+@@ -1 +1 @@
+-old/target
+\\ No newline at end of file
++old/target\t
+\\ No newline at end of file
+diff --git a/file b/file
+old mode 120000
+new mode 100644
+";
+        let lines: Vec<_> = annotator
+            .annotations(diff.as_bytes())
+            .map(|annotated| annotated.unwrap())
+            .collect();
+        // still annotated, but not squashed as a whitespace change
+        assert_eq!(lines.len(), diff.lines().count());
+        assert!(commit.starts_with(lines[5].commit.as_deref().unwrap()));
+        assert_eq!(lines[7].kind, LineKind::Added);
+        assert!(lines[1..9].iter().all(|annotated| annotated.symlink));
+        assert!(!lines[9].symlink);
+        assert!(lines[10].symlink);
+    }
+
+    #[test]
+    fn test_squash_whitespace_only_hunks() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_squash_whitespace_only_hunks(true);
+
+        let reader = Cursor::new(
+            "--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,2 @@
+-bar
++barbara
+ 0.5
+@@ -7,2 +7,2 @@
+-bar ba baz
+-a
++  bar ba baz
++\ta 
+",
+        );
+        let lines: Vec<_> = annotator
+            .annotations(reader)
+            .map(|annotated| {
+                let annotated = annotated.unwrap();
+                (annotated.kind, annotated.prefix.is_some(), annotated.line)
+            })
+            .collect();
+        assert_eq!(
+            lines[2..],
+            [
+                (LineKind::Header, false, "@@ -1,2 +1,2 @@".to_string()),
+                (LineKind::Removed, true, "-bar".to_string()),
+                (LineKind::Added, true, "+barbara".to_string()),
+                (LineKind::Context, true, " 0.5".to_string()),
+                (LineKind::Header, false, "@@ -7,2 +7,2 @@".to_string()),
+                (
+                    LineKind::Header,
+                    true,
+                    "… whitespace-only change, blamed to 6ec7db, b40c1d …".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_annotate_post_apply() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_post_apply(true);
+
+        let reader = Cursor::new(
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,3 @@
+-bar
++barbara
++barbarella
+ 0.5
+",
+        );
+        let mut writer = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, io::sink());
+        assert!(result.is_ok());
+        let output = String::from_utf8(writer).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        let applied = &annotator.applied.as_ref().unwrap()[..6];
+        assert_eq!(
+            lines[3..],
+            [
+                "------ -bar".to_string(),
+                format!("{applied} +barbara"),
+                format!("{applied} +barbarella"),
+                "6ec7db  0.5".to_string(),
+            ]
+        );
+        assert!(annotator.candidates.contains_key("6ec7db"));
+        assert!(!annotator.candidates.keys().any(|c| c.starts_with(applied)));
+    }
+
+    #[test]
+    fn test_blame_lines_post_apply() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_post_apply(true);
+        let patch = b"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,2 @@
+-bar
++barbara
+ 0.5
+";
+        let lines = annotator.blame_lines(&patch[..]).unwrap();
+        let applied = annotator.applied.as_deref().unwrap();
+        assert!(applied.starts_with(lines[4].commit.as_deref().unwrap()));
+    }
+
+    #[test]
+    fn test_annotate_verify() {
+        for (verify, debug, level) in [
+            (true, false, "warning: "),
+            (false, true, "debug: "),
+            (true, true, "warning: "),
+            (false, false, ""),
+        ] {
+            let mut annotator = DiffAnnotator::new(None, None, None, None)
+                .unwrap()
+                .with_verify(verify)
+                .with_debug(debug);
+
+            let reader = Cursor::new(
+                r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -9,4 +9,4 @@
+ b
+-C
+-D
++c
+ E
+",
+            );
+            let mut writer = Vec::new();
+            let mut cwriter = Vec::new();
+            let result = annotator.annotate_diff(reader, &mut writer, &mut cwriter);
+            assert!(result.is_ok());
+            assert_eq!(
+                String::from_utf8(writer).unwrap(),
+                r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -9,4 +9,4 @@
+b40c1d  b
+6ec7db -C
+?????? -D
+++++++ +c
+??????  E
+"
+            );
+            let message = match level {
+                "" => String::new(),
+                level => format!(
+                    "{level}tests/bar.txt:9: blame returned 2 of 4 lines, annotating the rest as \
+                     unknown\n"
+                ),
+            };
+            assert_eq!(String::from_utf8(cwriter).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_annotate_summary_format() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_stat(true)
+            .with_summary_format(Some(
+                "{commits} commits, {lines} lines across {files} files".to_string(),
+            ));
+
+        let reader = Cursor::new(PATCH);
+        let mut cwriter = Vec::new();
+        let result = annotator.annotate_diff(reader, io::sink(), &mut cwriter);
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(cwriter).unwrap(),
+            "2 commits, 33 lines across 2 files\n"
+        );
+    }
+
+    #[test]
+    fn test_annotate_without_diff_headers() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_emit_notes(Some(PathBuf::from("unused")));
+
+        let reader = Cursor::new(
+            r"index 6d0a9487a999..5aa46cc774fb 100644
+--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,2 @@
+-bar
++barbara
+ 0.5
+--- a/tests/foo.txt
++++ b/tests/foo.txt
+@@ -1,2 +1,2 @@
+ foo
+-bar
++baz
+",
+        );
+        let mut writer = Vec::new();
+        annotator.simple_diff(reader, &mut writer).unwrap();
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            r"index 6d0a9487a999..5aa46cc774fb 100644
+--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,2 @@
+b40c1d -bar
+++++++ +barbara
+6ec7db  0.5
+--- a/tests/foo.txt
++++ b/tests/foo.txt
+@@ -1,2 +1,2 @@
+b40c1d  foo
+b40c1d -bar
+++++++ +baz
+"
+        );
+        // the blob of the first file must not leak into the second
+        assert_eq!(
+            annotator.notes,
+            Some(vec!["5aa46cc774fb 2 6ec7db".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_blame_lines() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        let lines = annotator.blame_lines(Cursor::new(PATCH)).unwrap();
+        assert_eq!(lines.len(), PATCH.lines().count());
+        let line = |kind, commit: Option<&str>, text: &str, old_line| AnnotatedLine {
+            line: text.to_string(),
+            kind,
+            commit: commit.map(str::to_string),
+            prefix: None,
+            crlf: false,
+            file: Some("tests/bar.txt".to_string()),
+            old_line,
+            symlink: false,
+        };
+        let fields: Vec<_> = lines[3..8]
+            .iter()
+            .map(|annotated| AnnotatedLine {
+                prefix: None,
+                ..annotated.clone()
+            })
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                line(LineKind::Header, None, "+++ b/tests/bar.txt", None),
+                line(LineKind::Header, None, "@@ -1,10 +1,10 @@", None),
+                line(LineKind::Removed, Some("b40c1d"), "-bar", Some(1)),
+                line(LineKind::Added, None, "+barbara", None),
+                line(LineKind::Context, Some("6ec7db"), " 0.5", Some(2)),
+            ]
+        );
+        assert_eq!(lines[5].prefix.as_deref(), Some("b40c1d "));
+        assert!(lines
+            .iter()
+            .all(|line| !line.is_boundary() && !line.is_uncommitted()));
+
+        let mut annotator = DiffAnnotator::new(None, Some("b40c1d".to_string()), None, None)
+            .unwrap()
+            .with_min_abbrev(Some(7));
+        let lines = annotator.blame_lines(Cursor::new(PATCH)).unwrap();
+        assert_eq!(lines[5].commit.as_deref(), Some("^b40c1d"));
+        assert!(lines[5].is_boundary());
+        assert!(!lines[7].is_boundary());
+        assert!(line(LineKind::Context, Some("000000"), " 0.5", Some(2)).is_uncommitted());
+    }
+
+    #[test]
+    fn test_annotations_progress() {
+        assert_eq!(
+            DiffAnnotator::drop_progress("10%\r50%\r--- a/foo".to_string()),
+            "--- a/foo"
+        );
+        assert_eq!(DiffAnnotator::drop_progress(" foo\r".to_string()), " foo\r");
+
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        let reader = Cursor::new(
+            "Receiving objects:  50%\rReceiving objects: 100%\r--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,2 @@
+-bar
++bar\rbara
+Resolving deltas: 100%\r 0.5
+",
+        );
+        let lines: Vec<_> = annotator
+            .annotations(reader)
+            .map(|annotated| {
+                let annotated = annotated.unwrap();
+                (annotated.kind, annotated.commit, annotated.line)
+            })
+            .collect();
+        assert_eq!(lines[0].2, "--- a/tests/bar.txt");
+        assert_eq!(
+            lines[3..],
+            [
+                (
+                    LineKind::Removed,
+                    Some("b40c1d".to_string()),
+                    "-bar".to_string()
+                ),
+                (LineKind::Added, None, "+bar\rbara".to_string()),
+                (
+                    LineKind::Context,
+                    Some("6ec7db".to_string()),
+                    " 0.5".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_annotate_batch_blame_ranges() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_batch_blame_ranges(true);
+
+        let reader = Cursor::new(
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,2 @@
+-bar
++barbara
+ 0.5
+@@ -6,2 +6,2 @@
+ foobar
+-bar ba baz
++bar baz
+",
+        );
+        let mut writer = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, io::sink());
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,2 @@
+b40c1d -bar
+++++++ +barbara
+6ec7db  0.5
+@@ -6,2 +6,2 @@
+6ec7db  foobar
+6ec7db -bar ba baz
+++++++ +bar baz
+"
+        );
+        // a single blame covered both hunks, leaving the lines in between unblamed
+        assert_eq!(annotator.batched.len(), 1);
+        assert_eq!(
+            annotator.batched["tests/bar.txt"],
+            ["b40c1d", "6ec7db", "", "", "", "6ec7db", "6ec7db"]
+        );
+    }
+
+    #[test]
+    fn test_blame_cache_file() {
+        let path = std::env::temp_dir().join(format!(
+            "blaming-diff-filter-{}.blame-cache",
+            std::process::id()
+        ));
+        let annotator = || {
+            DiffAnnotator::new(None, None, None, None)
+                .unwrap()
+                .with_blame_cache_file(Some(path.clone()))
+        };
+        let mut writer = Vec::new();
+        let result = annotator().annotate_diff(Cursor::new(PATCH), &mut writer, io::sink());
+        assert!(result.is_ok());
+        let output = String::from_utf8(writer).unwrap();
+        assert!(output.contains("b40c1d -bar\n"));
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut annotator = annotator().with_runner(counting_runner(&calls));
+        annotator.load_cache().unwrap();
+        assert_eq!(annotator.batched.len(), 2);
+        // any further blame of the hunks would fail with this rev
+        annotator.rev = "refs/heads/does-not-exist".to_string();
+        let mut writer = Vec::new();
+        annotator
+            .simple_diff(Cursor::new(PATCH), &mut writer)
+            .unwrap();
+        assert_eq!(String::from_utf8(writer).unwrap(), output);
+        let blames = calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|call| call.contains(" blame "))
+            .count();
+        assert_eq!(blames, 0);
+
+        // blames of another commit are discarded
+        let cache = std::fs::read_to_string(&path).unwrap();
+        let mut lines: Vec<_> = cache.lines().collect();
+        let key = lines[1].replacen(|c: char| c.is_ascii_hexdigit(), "x", 1);
+        lines[1] = &key;
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        let mut annotator = annotator.with_blame_cache_file(Some(path.clone()));
+        annotator.rev = "HEAD".to_string();
+        annotator.batched.clear();
+        annotator.load_cache().unwrap();
+        assert!(annotator.batched.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_blame_cache_ignore_revs_file() {
+        let tmp = std::env::temp_dir();
+        let revs = tmp.join(format!(
+            "blaming-diff-filter-{}.cache-ignore-revs",
+            std::process::id()
+        ));
+        let key = |revs_content: &str| {
+            std::fs::write(&revs, revs_content).unwrap();
+            let mut annotator = DiffAnnotator::new(None, None, None, None)
+                .unwrap()
+                .with_blame_cache_file(Some(tmp.join("blaming-diff-filter-missing.blame-cache")))
+                .with_ignore_revs_file(Some(revs.clone()));
+            annotator.load_cache().unwrap();
+            annotator.cache_key.unwrap()
+        };
+        let first = key("# reformatting\n");
+        let same = key("# reformatting\n");
+        let changed = key("# reformatting\n# and more\n");
+        std::fs::remove_file(&revs).unwrap();
+        // the path stays the same, the contents do not
+        assert_eq!(first, same);
+        assert_ne!(first, changed);
+    }
+
+    #[test]
+    fn test_annotate_parallel_files() {
+        let annotate = |parallel| {
+            let mut annotator = DiffAnnotator::new(None, None, None, None)
+                .unwrap()
+                .with_batch_blame_ranges(true)
+                .with_parallel_files(Some(parallel));
+            let mut writer = Vec::new();
+            let result = annotator.annotate_diff(Cursor::new(PATCH), &mut writer, io::sink());
+            assert!(result.is_ok());
+            assert_eq!(annotator.batched.len(), 2);
+            String::from_utf8(writer).unwrap()
+        };
+        let output = annotate(1);
+        assert!(output.contains("b40c1d -bar\n"));
+        assert_eq!(annotate(4), output);
+    }
+
+    #[test]
+    fn test_prefetch_blames_single_call() {
+        let patch = b"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,1 +1,1 @@
+-bar
++barbara
+@@ -6,1 +6,1 @@
+-foobar
++foo
+";
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_batch_blame_ranges(true)
+            .with_runner(counting_runner(&calls));
+        let commits: Vec<_> = annotator
+            .annotations(&patch[..])
+            .map(|annotated| annotated.unwrap().commit)
+            .collect();
+        let blames = calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|call| call.contains(" blame "))
+            .count();
+        assert_eq!(blames, 1);
+        assert_eq!(
+            commits.iter().flatten().collect::<Vec<_>>(),
+            ["b40c1d", "6ec7db"]
+        );
+    }
+
+    #[test]
+    fn test_blame_hunk_range() {
+        let patch = b"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,2 @@
+-bar
++barbara
+ 0.5
+@@ -6,0 +6,1 @@
++foo
+";
+        for batch in [false, true] {
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let mut annotator = DiffAnnotator::new(None, None, None, None)
+                .unwrap()
+                .with_batch_blame_ranges(batch)
+                .with_runner(counting_runner(&calls));
+            let lines = annotator.blame_lines(&patch[..]).unwrap();
+            assert_eq!(lines[3].commit.as_deref(), Some("b40c1d"));
+            assert_eq!(lines[5].commit.as_deref(), Some("6ec7db"));
+            // the hunk of 2 lines ends at line 2, the one of none blames nothing
+            let calls = calls.lock().unwrap();
+            let blames: Vec<_> = calls
+                .iter()
+                .filter(|call| call.contains(" blame "))
+                .collect();
+            if cfg!(feature = "git2") && !batch {
+                assert!(blames.is_empty(), "{calls:?}");
+            } else {
+                assert_eq!(blames.len(), 1, "{calls:?}");
+                assert!(blames[0].contains(" -L 1,2 --"), "{calls:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_prefetch_blames_repeated_file() {
+        let patch = b"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,1 +1,1 @@
+-bar
++barbara
+--- a/tests/foo.txt
++++ b/tests/foo.txt
+@@ -1,1 +1,1 @@
+-foo
++food
+--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -6,1 +6,1 @@
+-foobar
++foo
+";
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        annotator.prefetch_blames(patch).unwrap();
+        // the ranges of both occurrences of the file are blamed together
+        annotator.rev = "refs/heads/does-not-exist".to_string();
+        let commits: Vec<_> = annotator
+            .annotations(&patch[..])
+            .map(|annotated| annotated.unwrap().commit)
+            .collect();
+        assert_eq!(commits.iter().flatten().count(), 3);
+        assert_eq!(commits.iter().flatten().last().unwrap(), "6ec7db");
+    }
+
+    #[test]
+    fn test_consistent_width() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        let batched = [
+            ("tests/bar.txt", vec!["b40c1d", "6ec7db"]),
+            ("tests/foo.txt", vec!["b40c1dbc", "^6ec7db"]),
+        ];
+        for (file, commits) in batched {
+            let commits = commits.into_iter().map(str::to_string).collect();
+            annotator.batched.insert(file.to_string(), commits);
+        }
+        annotator.maxlen = annotator.batched_maxlen();
+        let patch = "--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,2 @@
+-bar
++barbara
+ 0.5
+--- a/tests/foo.txt
++++ b/tests/foo.txt
+@@ -1,2 +1,2 @@
+ foo
+-bar
++baz
+";
+        let prefixes: Vec<_> = annotator
+            .annotations(patch.as_bytes())
+            .filter_map(|annotated| annotated.unwrap().prefix)
+            .collect();
+        assert_eq!(
+            prefixes,
+            [
+                "b40c1d   ",
+                "++++++++ ",
+                "6ec7db   ",
+                "b40c1dbc ",
+                "········ ",
+                "++++++++ "
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_candidate_age() {
+        const DAY: u64 = 24 * 60 * 60;
+        let now = 1_700_000_000;
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_now(Some(now))
+            .with_max_candidate_age(Some(365 * DAY));
+        let info = |time| CommitInfo {
+            time,
+            ..Default::default()
+        };
+        annotator
+            .info
+            .insert("b40c1d".to_string(), info(now - 2 * 365 * DAY));
+        annotator.info.insert("6ec7db".to_string(), info(now - DAY));
+        annotator.file = Some("tests/bar.txt".to_string());
+        annotator.commits = ["b40c1d", "6ec7db", "b40c1d", "6ec7db"]
+            .map(str::to_string)
+            .to_vec();
+        annotator.start = 1;
+        annotator.offset = 1;
+        annotator.maxlen = 6;
+        let prefixes = ["-bar", "-0.5", " 1", "-2"].map(|line| {
+            annotator
+                .annotate_line(line.to_string())
+                .unwrap()
+                .prefix
+                .unwrap()
+        });
+        assert_eq!(prefixes, ["b40c1d!", "6ec7db ", "b40c1d ", "6ec7db "]);
+        assert_eq!(
+            annotator.old_lines_warning().unwrap(),
+            "1 changed lines older than 365 days, last touched by b40c1d"
+        );
+    }
+
+    #[test]
+    fn test_uncommitted_warning() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        annotator.file = Some("tests/bar.txt".to_string());
+        annotator.commits = vec!["000000".to_string(), "^b40c1".to_string()];
+        annotator.start = 1;
+        annotator.offset = 1;
+        annotator.annotate_line(" bar".to_string()).unwrap();
+        annotator.annotate_line(" 0.5".to_string()).unwrap();
+        assert_eq!(
+            annotator.uncommitted_warning().unwrap(),
+            "1 lines blamed as not committed yet, the work tree may have changed since the diff \
+             was generated"
+        );
+        let annotator = annotator.with_source(Some(Source::Worktree));
+        assert_eq!(annotator.uncommitted_warning(), None);
+        let annotator = annotator.with_source(Some(Source::Index));
+        assert_eq!(annotator.uncommitted_warning(), None);
+    }
+
+    #[test]
+    fn test_context_column() {
+        let prefixes = |context_column| {
+            let mut annotator = DiffAnnotator::new(None, None, None, None)
+                .unwrap()
+                .with_context_column(context_column);
+            annotator.file = Some("tests/bar.txt".to_string());
+            annotator.commits = vec!["b40c1d".to_string(), "6ec7db".to_string()];
+            annotator.start = 1;
+            annotator.offset = 1;
+            annotator.maxlen = 6;
+            ["-bar", " 0.5", "+0.75"]
+                .map(|line| annotator.annotate_line(line.to_string()).unwrap().prefix)
+        };
+        let some = |prefix: &str| Some(prefix.to_string());
+        assert_eq!(
+            prefixes(ContextColumn::Always),
+            [some("b40c1d "), some("6ec7db "), some("++++++ ")]
+        );
+        assert_eq!(
+            prefixes(ContextColumn::Changed),
+            [some("b40c1d "), some("       "), some("++++++ ")]
+        );
+        assert_eq!(
+            prefixes(ContextColumn::Never),
+            [some("b40c1d "), None, some("++++++ ")]
+        );
+    }
+
+    #[test]
+    fn test_first_only() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_first_only(true);
+        let mut prefixes = |file: &str, lines: [&str; 2]| {
+            annotator.reset_file();
+            annotator.file = Some(file.to_string());
+            annotator.commits = vec!["b40c1d".to_string(), "6ec7db".to_string()];
+            annotator.start = 1;
+            annotator.offset = 1;
+            annotator.maxlen = 6;
+            lines.map(|line| {
+                annotator
+                    .annotate_line(line.to_string())
+                    .unwrap()
+                    .prefix
+                    .unwrap()
+            })
+        };
+        assert_eq!(
+            prefixes("tests/bar.txt", ["-bar", " 0.5"]),
+            ["b40c1d ", "6ec7db "]
+        );
+        assert_eq!(
+            prefixes("tests/foo.txt", [" foo", "-bar"]),
+            ["       ", "       "]
+        );
+    }
+
+    #[test]
+    fn test_annotate_record_separator() {
+        let mut annotator = DiffAnnotator::new(None, None, None, Some("%h".to_string()))
+            .unwrap()
+            .with_record_separator(Some("===".to_string()));
+
+        let reader = Cursor::new(
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,1 +1,1 @@
+-bar
++barbara
+===
+@@ -1,1 +1,1 @@
+-bar
++barbara
+===
+--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -2,1 +2,1 @@
+-0.5
++0.25
+",
+        );
+        let mut writer = Vec::new();
+        let mut cwriter = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, &mut cwriter);
+        assert!(result.is_ok());
+        // the hunk of the second record lacks a file header, so it is not blamed
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,1 +1,1 @@
+b40c1d -bar
+++++++ +barbara
+===
+@@ -1,1 +1,1 @@
+?????? -bar
+++++++ +barbara
+===
+--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -2,1 +2,1 @@
+6ec7db -0.5
+++++++ +0.25
+"
+        );
+        assert_eq!(
+            String::from_utf8(cwriter).unwrap(),
+            "b40c1d\n===\n===\n6ec7db\n"
+        );
+    }
+
+    #[test]
+    fn test_annotate_stat_record_separator() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_stat(true)
+            .with_record_separator(Some("===".to_string()));
+
+        let reader = Cursor::new(
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,1 +1,1 @@
+-bar
++barbara
+===
+--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -2,1 +2,1 @@
+-0.5
++0.25
+",
+        );
+        let mut cwriter = Vec::new();
+        let result = annotator.annotate_diff(reader, io::sink(), &mut cwriter);
+        assert!(result.is_ok());
+        // the commits of all records count, not only of the last one
+        assert_eq!(
+            String::from_utf8(cwriter).unwrap().lines().last(),
+            Some("2 lines blamed to 2 commits in 1 files")
+        );
+    }
+
+    #[test]
+    fn test_annotate_require_clean_tree() {
+        let (dir, _) = fixture_repo("require-clean");
+        let patch = "--- a/baz.txt\n+++ b/baz.txt\n@@ -1,2 +1,2 @@\n one\n-two\n+three\n";
+        let annotate = || {
+            let mut annotator = DiffAnnotator::new_in(Some(dir.clone()), None, None, None, None)
+                .unwrap()
+                .with_require_clean_tree(true);
+            let mut writer = Vec::new();
+            let result = annotator.annotate_diff(Cursor::new(patch), &mut writer, io::sink());
+            result.map(|_| writer)
+        };
+        let clean = annotate();
+        std::fs::write(dir.join("baz.txt"), "one\nthree\n").unwrap();
+        let dirty = annotate();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!clean.unwrap().is_empty());
+        assert_eq!(
+            dirty.unwrap_err().to_string(),
+            "uncommitted changes to baz.txt, commit or stash them first"
+        );
+    }
+
+    fn fixture_repo(name: &str) -> (PathBuf, impl Fn(&[&str]) -> String) {
+        let dir = std::env::temp_dir().join(format!("bdf-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let git_dir = dir.clone();
+        let git = move |args: &[&str]| {
+            let output = Command::new("git")
+                .arg("-C")
+                .arg(&git_dir)
+                .args(args)
+                .env("GIT_AUTHOR_NAME", "Jane Doe")
+                .env("GIT_AUTHOR_EMAIL", "jane@example.com")
+                .env("GIT_COMMITTER_NAME", "Jane Doe")
+                .env("GIT_COMMITTER_EMAIL", "jane@example.com")
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap()
+        };
+        git(&["init", "-q"]);
+        // root commits blame as boundary
+        git(&["commit", "-q", "--allow-empty", "-m", "Initial commit"]);
+        std::fs::write(dir.join("baz.txt"), "one\ntwo\n").unwrap();
+        git(&["add", "baz.txt"]);
+        git(&["commit", "-q", "-m", "Add baz"]);
+        (dir, git)
+    }
+
+    #[test]
+    fn test_annotate_git_dir() {
+        let (dir, git) = fixture_repo("git-dir");
+        let sha = git(&["rev-parse", "HEAD"]);
+
+        let patch = "--- a/baz.txt\n+++ b/baz.txt\n@@ -1,2 +1,2 @@\n one\n-two\n+three\n";
+        let mut annotator =
+            DiffAnnotator::new_in(Some(dir.clone()), None, None, None, Some("%s".to_string()))
+                .unwrap();
+        let mut writer = Vec::new();
+        let mut cwriter = Vec::new();
+        let result = annotator.annotate_diff(Cursor::new(patch), &mut writer, &mut cwriter);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_ok());
+        let id = &sha[..DiffAnnotator::ABBREV];
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            format!(
+                "--- a/baz.txt\n+++ b/baz.txt\n@@ -1,2 +1,2 @@\n{id}  one\n{id} -two\n++++++ +three\n"
+            )
+        );
+        assert_eq!(String::from_utf8(cwriter).unwrap(), "Add baz\n");
+    }
+
+    #[test]
+    fn test_annotate_header_like_lines() {
+        let (dir, git) = fixture_repo("header-like");
+        std::fs::write(dir.join("baz.sql"), "select 1;\n-- comment\n").unwrap();
+        git(&["add", "baz.sql"]);
+        git(&["commit", "-q", "-m", "Add query"]);
+        let sha = git(&["rev-parse", "HEAD"]);
+
+        // removed and added lines that look like the headers of the next file
+        let patch =
+            "--- a/baz.sql\n+++ b/baz.sql\n@@ -1,2 +1,2 @@\n select 1;\n--- comment\n+++ note\n";
+        let mut annotator =
+            DiffAnnotator::new_in(Some(dir.clone()), None, None, None, None).unwrap();
+        let mut writer = Vec::new();
+        let result = annotator.annotate_diff(Cursor::new(patch), &mut writer, &mut Vec::new());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_ok());
+        let id = &sha[..DiffAnnotator::ABBREV];
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            format!(
+                "--- a/baz.sql\n+++ b/baz.sql\n@@ -1,2 +1,2 @@\n{id}  select 1;\n{id} --- comment\n++++++ +++ note\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_core_abbrev() {
+        let (dir, git) = fixture_repo("core-abbrev");
+        let sha = git(&["rev-parse", "HEAD"]);
+        let patch = "--- a/baz.txt\n+++ b/baz.txt\n@@ -1,2 +1,2 @@\n one\n-two\n+three\n";
+        let mut outputs = Vec::new();
+        for (abbrev, min_abbrev) in [("12", None), ("auto", None), ("12", Some(8))] {
+            git(&["config", "core.abbrev", abbrev]);
+            let mut annotator = DiffAnnotator::new_in(Some(dir.clone()), None, None, None, None)
+                .unwrap()
+                .with_min_abbrev(min_abbrev);
+            let mut writer = Vec::new();
+            let result = annotator.annotate_diff(Cursor::new(patch), &mut writer, io::sink());
+            outputs.push(result.map(|_| String::from_utf8(writer).unwrap()));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        for (output, width) in outputs.into_iter().zip([12, DiffAnnotator::ABBREV, 8]) {
+            let id = &sha[..width];
+            let marker = "+".repeat(width);
+            assert_eq!(
+                output.unwrap(),
+                format!(
+                    "--- a/baz.txt\n+++ b/baz.txt\n@@ -1,2 +1,2 @@\n{id}  one\n{id} -two\n{marker} +three\n"
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_annotate_source() {
+        let (dir, git) = fixture_repo("source");
+        let sha = git(&["rev-parse", "HEAD"]);
+        std::fs::write(dir.join("baz.txt"), "zero\none\ntwo\n").unwrap();
+        git(&["add", "baz.txt"]);
+        std::fs::write(dir.join("baz.txt"), "zero\none\nthree\n").unwrap();
+        // the diff of git add -p, with old side line numbers of the index
+        let patch = git(&["diff"]);
+        let mut outputs = Vec::new();
+        let mut warnings = Vec::new();
+        for source in [Source::Head, Source::Index, Source::Worktree] {
+            let mut annotator = DiffAnnotator::new_in(Some(dir.clone()), None, None, None, None)
+                .unwrap()
+                .with_source(Some(source));
+            let mut writer = Vec::new();
+            let mut cwriter = Vec::new();
+            let result = annotator.annotate_diff(Cursor::new(&patch), &mut writer, &mut cwriter);
+            outputs.push(result.map(|_| String::from_utf8(writer).unwrap()));
+            warnings.push(
+                String::from_utf8(cwriter)
+                    .unwrap()
+                    .contains("not committed yet"),
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut outputs = outputs.into_iter();
+        let id = &sha[..DiffAnnotator::ABBREV];
+        let header = patch.split_once("@@").unwrap().0;
+        // line numbers of the index are off by the staged line in HEAD, which is also shorter
+        assert_eq!(
+            outputs.next().unwrap().unwrap(),
+            format!("{header}@@ -1,3 +1,3 @@\n{id}  zero\n{id}  one\n?????? -two\n++++++ +three\n")
+        );
+        assert_eq!(
+            outputs.next().unwrap().unwrap(),
+            format!("{header}@@ -1,3 +1,3 @@\n······  zero\n{id}  one\n{id} -two\n++++++ +three\n")
+        );
+        // the work tree is the new side here, so the removed line is not committed yet
+        assert_eq!(
+            outputs.next().unwrap().unwrap(),
+            format!(
+                "{header}@@ -1,3 +1,3 @@\n······  zero\n{id}  one\n······ -two\n++++++ +three\n"
+            )
+        );
+        // lines of the index and work tree are expected to be not committed yet
+        assert_eq!(warnings, [false, false, false]);
+    }
+
+    #[test]
+    fn test_annotate_count_only() {
+        for (back_to, count) in [(None, "2\n"), (Some("b40c1d".to_string()), "1\n")] {
+            let mut annotator = DiffAnnotator::new(None, back_to, None, Some("%h".to_string()))
+                .unwrap()
+                .with_count_only(true);
+            let mut writer = Vec::new();
+            let mut cwriter = Vec::new();
+            let result = annotator.annotate_diff(Cursor::new(PATCH), &mut writer, &mut cwriter);
+            assert!(result.is_ok());
+            assert_eq!(String::from_utf8(writer).unwrap(), count);
+            assert!(cwriter.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_annotate_file_report() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_file_report(true);
+
+        let reader = Cursor::new(PATCH);
+        let mut writer = Vec::new();
+        let mut cwriter = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, &mut cwriter);
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(cwriter).unwrap(),
+            "tests/bar.txt:
+     3 b40c1d
+tests/foo.txt:
+     3 b40c1d
+     1 6ec7db
+"
+        );
+    }
+
+    #[test]
+    fn test_annotate_json() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_json(true);
+
+        let reader = Cursor::new(PATCH);
+        let mut writer = Vec::new();
+        let mut cwriter = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, &mut cwriter);
+        assert!(result.is_ok());
+        let json = String::from_utf8(writer).unwrap();
+        assert!(json.starts_with(r#"{"version":2,"files":[{"path":"tests/bar.txt","lines":["#));
+        assert!(json.contains(
+            r#"{"file":"tests/bar.txt","old_line":1,"kind":"removed","commit":"b40c1d","text":"-bar"},{"file":"tests/bar.txt","old_line":null,"kind":"added","commit":null,"text":"+barbara"}"#
+        ));
+        assert!(json.contains(r#"]},{"path":"tests/foo.txt","lines":[{"file":"tests/foo.txt","old_line":null,"kind":"header","commit":null,"text":"diff --git a/tests/foo.txt b/tests/foo.txt"}"#));
+        assert!(json.ends_with("],\"candidates\":[\"6ec7db\",\"b40c1d\"]}\n"));
+        assert!(cwriter.is_empty());
+    }
+
+    #[test]
+    fn test_annotate_diff() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+
+        let reader = Cursor::new(PATCH);
+        let mut writer = Vec::new();
+        let mut cwriter = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, &mut cwriter);
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            r"diff --git a/tests/bar.txt b/tests/bar.txt
+index 6d0a9487a999..5aa46cc774fb 10064
+--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,10 +1,10 @@
+b40c1d -bar
+++++++ +barbara
+6ec7db  0.5
+b40c1d  1
+b40c1d  2
+b40c1d  3
+6ec7db  foobar
+6ec7db  bar ba baz
+b40c1d -a
+b40c1d -b
+++++++ +A
+++++++ +B
+6ec7db  C
+diff --git a/tests/foo.txt b/tests/foo.txt
+index 06259808ba40..482e77c74da8 100644
+--- a/tests/foo.txt
++++ b/tests/foo.txt
+@@ -1,5 +1,5 @@
+b40c1d  foo
+b40c1d -bar
+++++++ +baz
+b40c1d  a
+b40c1d  b
+b40c1d  c
+@@ -7,7 +7,7 @@ d
+b40c1d  +
+b40c1d  -
+b40c1d  +++
+b40c1d -extra
+++++++ +wtextra
+b40c1d  bla
+b40c1d  ---
+b40c1d  @@ foo
+@@ -17,7 +17,7 @@ bar
+b40c1d  3
+b40c1d  4
+b40c1d  5
+b40c1d -6
+++++++ +5z
+6ec7db  6a
+b40c1d  7
+b40c1d  8
+@@ -25,4 +25,3 @@ bar
+b40c1d  10
+b40c1d  11
+b40c1d  12
+6ec7db -13
+"
+        );
+    }
+
+    #[test]
+    fn test_annotate_inner_crlf() {
+        let inner = vec!["cat".to_string(), "-v".to_string()];
+        let mut annotator = DiffAnnotator::new(Some(inner), None, None, None)
+            .unwrap()
+            .with_wrap(Some(12));
+
+        let reader = Cursor::new(
+            "--- a/tests/bar.txt\n\
+             +++ b/tests/bar.txt\n\
+             @@ -1,2 +1,2 @@\n\
+             -bar\r\n\
+             +barbara\r\n\
+             \x200.5\r\n",
+        );
+        let mut writer = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, io::sink());
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            "--- a/tests/bar.txt\n\
+             +++ b/tests/bar.txt\n\
+             @@ -1,2 +1,2 @@\n\
+             b40c1d -bar\r\n\
+             ++++++ +barb\n       ara\r\n\
+             6ec7db  0.5\r\n"
+        );
+    }
+
+    #[test]
+    fn test_annotate_crlf() {
+        let annotate = |side, patch: &str| {
+            let mut annotator = DiffAnnotator::new(None, None, None, None)
+                .unwrap()
+                .with_side(side)
+                .with_side_width(Some(24));
+            let mut writer = Vec::new();
+            let result = annotator.annotate_diff(Cursor::new(patch), &mut writer, io::sink());
+            assert!(result.is_ok());
+            String::from_utf8(writer).unwrap()
+        };
+        // a diff converted as a whole keeps its line numbers, and the CR stays at the end
+        for side in [Side::Left, Side::Right] {
+            assert_eq!(
+                annotate(side, &PATCH.replace('\n', "\r\n")),
+                annotate(side, PATCH).replace('\n', "\r\n")
+            );
+        }
+    }
+
+    #[test]
+    fn test_annotate_inner() {
+        let inner = vec![
+            "tr".to_string(),
+            "[:lower:]".to_string(),
+            "[:upper:]".to_string(),
+        ];
+        let format = "%h %s".to_string();
+        let mut annotator = DiffAnnotator::new(Some(inner), None, None, Some(format)).unwrap();
+
+        let reader = Cursor::new(PATCH);
+        let mut writer = Vec::new();
+        let mut cwriter = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, &mut cwriter);
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            r"DIFF --GIT A/TESTS/BAR.TXT B/TESTS/BAR.TXT
+INDEX 6D0A9487A999..5AA46CC774FB 10064
+--- A/TESTS/BAR.TXT
++++ B/TESTS/BAR.TXT
+@@ -1,10 +1,10 @@
+b40c1d -BAR
+++++++ +BARBARA
+6ec7db  0.5
+b40c1d  1
+b40c1d  2
+b40c1d  3
+6ec7db  FOOBAR
+6ec7db  BAR BA BAZ
+b40c1d -A
+b40c1d -B
+++++++ +A
+++++++ +B
+6ec7db  C
+DIFF --GIT A/TESTS/FOO.TXT B/TESTS/FOO.TXT
+INDEX 06259808BA40..482E77C74DA8 100644
+--- A/TESTS/FOO.TXT
++++ B/TESTS/FOO.TXT
+@@ -1,5 +1,5 @@
+b40c1d  FOO
+b40c1d -BAR
+++++++ +BAZ
+b40c1d  A
+b40c1d  B
+b40c1d  C
+@@ -7,7 +7,7 @@ D
+b40c1d  +
+b40c1d  -
+b40c1d  +++
+b40c1d -EXTRA
+++++++ +WTEXTRA
+b40c1d  BLA
+b40c1d  ---
+b40c1d  @@ FOO
+@@ -17,7 +17,7 @@ BAR
+b40c1d  3
+b40c1d  4
+b40c1d  5
+b40c1d -6
+++++++ +5Z
+6ec7db  6A
+b40c1d  7
+b40c1d  8
+@@ -25,4 +25,3 @@ BAR
+b40c1d  10
+b40c1d  11
+b40c1d  12
+6ec7db -13
+"
+        );
+        assert_eq!(
+            String::from_utf8(cwriter).unwrap(),
+            r"b40c1d tests: Add some test data
+6ec7db tests: Add some changes to test files for blame testing
+"
+        );
+    }
+
+    #[test]
+    fn test_annotate_backto() {
+        let mut annotator =
+            DiffAnnotator::new(None, Some("b40c1dbc28".to_string()), None, None).unwrap();
+
+        let reader = Cursor::new(PATCH);
+        let mut writer = Vec::new();
+        let mut cwriter = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, &mut cwriter);
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            r"diff --git a/tests/bar.txt b/tests/bar.txt
 index 6d0a9487a999..5aa46cc774fb 10064
 --- a/tests/bar.txt
 +++ b/tests/bar.txt
 @@ -1,10 +1,10 @@
--bar
-+barbara
- 0.5
- 1
- 2
- 3
- foobar
- bar ba baz
--a
--b
-+A
-+B
- C
+······ -bar
+++++++ +barbara
+6ec7db  0.5
+······  1
+······  2
+······  3
+6ec7db  foobar
+6ec7db  bar ba baz
+······ -a
+······ -b
+++++++ +A
+++++++ +B
+6ec7db  C
 diff --git a/tests/foo.txt b/tests/foo.txt
 index 06259808ba40..482e77c74da8 100644
 --- a/tests/foo.txt
 +++ b/tests/foo.txt
+@@ -1,5 +1,5 @@
+······  foo
+······ -bar
+++++++ +baz
+······  a
+······  b
+······  c
+@@ -7,7 +7,7 @@ d
+······  +
+······  -
+······  +++
+······ -extra
+++++++ +wtextra
+······  bla
+······  ---
+······  @@ foo
+@@ -17,7 +17,7 @@ bar
+······  3
+······  4
+······  5
+······ -6
+++++++ +5z
+6ec7db  6a
+······  7
+······  8
+@@ -25,4 +25,3 @@ bar
+······  10
+······  11
+······  12
+6ec7db -13
+"
+        );
+    }
+
+    #[test]
+    fn test_blame_range() {
+        let annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        let commits = annotator.blame_range("tests/bar.txt", 5, 8).unwrap();
+        assert_eq!(commits, vec!["b40c1d", "6ec7db", "6ec7db", "b40c1d"]);
+    }
+
+    // libgit2 blames without running git
+    #[cfg(not(feature = "git2"))]
+    #[test]
+    fn test_blame_range_runner() {
+        for abbrev in [4, 6, 9, 12] {
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let count = counting_runner(&calls);
+            // git shows n+1 digits for --abbrev=n, a boundary ^ takes the place of the last
+            let annotator = DiffAnnotator::new(None, None, None, None)
+                .unwrap()
+                .with_min_abbrev(Some(abbrev))
+                .with_runner(move |cmd| {
+                    let n: usize = cmd
+                        .get_args()
+                        .find_map(|arg| arg.to_str()?.strip_prefix("--abbrev=")?.parse().ok())
+                        .unwrap();
+                    let mut output = count(cmd)?;
+                    output.stdout = format!(
+                        "{} 1) bar\n^{} 2) 0.5\n",
+                        &"0123456789abcdef0123"[..=n],
+                        &"fedcba9876543210fedc"[..n]
+                    )
+                    .into_bytes();
+                    Ok(output)
+                });
+            let commits = annotator.blame_range("tests/bar.txt", 1, 2).unwrap();
+            assert_eq!(
+                commits,
+                [
+                    &"0123456789abcdef"[..abbrev],
+                    &"^fedcba9876543210"[..abbrev]
+                ]
+            );
+            let calls = calls.lock().unwrap();
+            assert_eq!(calls.len(), 1);
+            assert!(calls[0].contains(" blame "), "{calls:?}");
+            assert!(calls[0].ends_with(" -L 1,2 -- tests/bar.txt"), "{calls:?}");
+        }
+    }
+
+    #[cfg(feature = "git2")]
+    #[test]
+    fn test_rev_parse_libgit2() {
+        for rev in ["HEAD", "HEAD~1", "b40c1d", "6ec7db^{tree}"] {
+            let output = Command::new("git")
+                .arg("rev-parse")
+                .arg(rev)
+                .output()
+                .unwrap();
+            assert_eq!(
+                rev_parse(rev).unwrap(),
+                String::from_utf8(output.stdout).unwrap().trim()
+            );
+        }
+        assert!(rev_parse("refs/heads/does-not-exist").is_err());
+    }
+
+    #[cfg(feature = "git2")]
+    #[test]
+    fn test_blame_libgit2() {
+        for (back_to, abbrev) in [
+            (None, 6),
+            (Some("HEAD~2".to_string()), 6),
+            (None, 5),
+            (None, 12),
+        ] {
+            let annotator = DiffAnnotator::new(None, back_to, None, None)
+                .unwrap()
+                .with_min_abbrev(Some(abbrev));
+            for file in ["tests/foo.txt", "tests/bar.txt"] {
+                assert_eq!(
+                    annotator.blame_libgit2(file, None).unwrap(),
+                    DiffAnnotator::parse_blame(
+                        &annotator.blame_subprocess(file, &[], false).unwrap()
+                    )
+                );
+                assert_eq!(
+                    annotator.blame_libgit2(file, Some((2, 6))).unwrap(),
+                    DiffAnnotator::parse_blame(
+                        &annotator.blame_subprocess(file, &[(2, 6)], false).unwrap()
+                    )
+                );
+            }
+        }
+    }
+
+    fn git_with_index(index: &std::path::Path, args: &[&str], input: &str) -> String {
+        let mut child = Command::new("git")
+            .args(args)
+            .env("GIT_INDEX_FILE", index)
+            .env("GIT_AUTHOR_NAME", "Jane Doe")
+            .env("GIT_AUTHOR_EMAIL", "jane@example.com")
+            .env("GIT_COMMITTER_NAME", "Jane Doe")
+            .env("GIT_COMMITTER_EMAIL", "jane@example.com")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    fn runner() -> Runner {
+        Arc::new(Command::output)
+    }
+
+    fn counting_runner(
+        calls: &Arc<Mutex<Vec<String>>>,
+    ) -> impl Fn(&mut Command) -> io::Result<Output> {
+        // record the args of each command, then run it
+        let calls = calls.clone();
+        move |cmd| {
+            let args: Vec<_> = cmd.get_args().map(|arg| arg.to_string_lossy()).collect();
+            calls.lock().unwrap().push(args.join(" "));
+            cmd.output()
+        }
+    }
+
+    fn rev_parse(rev: &str) -> io::Result<String> {
+        DiffAnnotator::rev_parse(&runner(), None, rev)
+    }
+
+    fn temp_index() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!(
+            "blaming-diff-filter-{}-{n}.index",
+            std::process::id()
+        ))
+    }
+
+    fn stage_on(index: &std::path::Path, parent: &str, files: &[(&str, &str, &str)]) {
+        // files are given as mode, path and content, such as "100644"
+        git_with_index(index, &["read-tree", parent], "");
+        for (mode, path, content) in files {
+            let blob = git_with_index(index, &["hash-object", "-w", "--stdin"], content);
+            let info = format!("{mode},{blob},{path}");
+            git_with_index(index, &["update-index", "--add", "--cacheinfo", &info], "");
+        }
+    }
+
+    fn commit_on(parents: &[&str], files: &[(&str, &str, &str)]) -> String {
+        // commit the files on top of the first parent, without touching the real index or any
+        // branch
+        let index = temp_index();
+        stage_on(&index, parents[0], files);
+        let tree = git_with_index(&index, &["write-tree"], "");
+        let mut args = vec!["commit-tree", &tree, "-m", "Commit"];
+        for parent in parents {
+            args.extend(["-p", parent]);
+        }
+        let commit = git_with_index(&index, &args, "");
+        std::fs::remove_file(&index).unwrap();
+        commit
+    }
+
+    fn commit_on_head(files: &[(&str, &str, &str)]) -> String {
+        commit_on(&["HEAD"], files)
+    }
+
+    #[test]
+    fn test_blame_ignore_revs() {
+        // a reformatting
+        let reformatted = std::fs::read_to_string("tests/bar.txt")
+            .unwrap()
+            .replacen("0.5\n", "0.50\n", 1);
+        let commit = commit_on_head(&[("100644", "tests/bar.txt", &reformatted)]);
+
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        annotator.rev = commit.clone();
+        assert_eq!(
+            annotator.blame_range("tests/bar.txt", 2, 3).unwrap(),
+            vec![&commit[..6], "b40c1d"]
+        );
+        let annotator = annotator.with_ignore_revs(vec![commit]);
+        assert_eq!(
+            annotator.blame_range("tests/bar.txt", 2, 3).unwrap(),
+            vec!["6ec7db", "b40c1d"]
+        );
+    }
+
+    #[test]
+    fn test_blame_ignore_revs_file() {
+        // a reformatting
+        let reformatted = std::fs::read_to_string("tests/bar.txt")
+            .unwrap()
+            .replacen("0.5\n", "0.50\n", 1);
+        let commit = commit_on_head(&[("100644", "tests/bar.txt", &reformatted)]);
+        let revs = std::env::temp_dir().join(format!(
+            "blaming-diff-filter-{}.ignore-revs",
+            std::process::id()
+        ));
+        std::fs::write(&revs, format!("# reformatting\n{commit}\n")).unwrap();
+
+        for incremental in [false, true] {
+            let mut annotator = DiffAnnotator::new(None, None, None, None)
+                .unwrap()
+                .with_incremental(incremental)
+                .with_ignore_revs_file(Some(revs.clone()));
+            annotator.rev = commit.clone();
+            assert_eq!(
+                annotator.blame_range("tests/bar.txt", 2, 3).unwrap(),
+                vec!["6ec7db", "b40c1d"]
+            );
+            // lines passed on from an ignored rev get the boundary marker like any other
+            let mut annotator = annotator.with_min_abbrev(Some(7));
+            annotator.boundary = Some("6ec7db".to_string());
+            assert_eq!(
+                annotator.blame_range("tests/bar.txt", 2, 3).unwrap(),
+                vec!["^6ec7db", "^6ec7db"]
+            );
+        }
+        std::fs::remove_file(&revs).unwrap();
+    }
+
+    #[test]
+    fn test_blame_ignore_whitespace() {
+        // a reindentation
+        let reindented = std::fs::read_to_string("tests/bar.txt").unwrap().replacen(
+            "0.5\n1\n",
+            "0.5\n    1\n",
+            1,
+        );
+        let commit = commit_on_head(&[("100644", "tests/bar.txt", &reindented)]);
+
+        for incremental in [false, true] {
+            let mut annotator = DiffAnnotator::new(None, None, None, None)
+                .unwrap()
+                .with_incremental(incremental);
+            annotator.rev = commit.clone();
+            assert_eq!(
+                annotator.blame_range("tests/bar.txt", 2, 3).unwrap(),
+                vec!["6ec7db", &commit[..6]]
+            );
+            let annotator = annotator.with_ignore_whitespace(true);
+            assert_eq!(
+                annotator.blame_range("tests/bar.txt", 2, 3).unwrap(),
+                vec!["6ec7db", "b40c1d"]
+            );
+            // composes with blaming back to a boundary
+            let mut annotator = annotator.with_min_abbrev(Some(7));
+            annotator.boundary = Some(rev_parse("6ec7db").unwrap());
+            assert_eq!(
+                annotator.blame_range("tests/bar.txt", 2, 3).unwrap(),
+                vec!["^6ec7db", "^6ec7db"]
+            );
+        }
+    }
+
+    #[test]
+    fn test_blame_first_parent() {
+        // merge a side branch changing a line into HEAD
+        let changed = std::fs::read_to_string("tests/bar.txt")
+            .unwrap()
+            .replacen("0.5\n", "0.75\n", 1);
+        let files = [("100644", "tests/bar.txt", changed.as_str())];
+        let side = commit_on_head(&files);
+        let merge = commit_on(&["HEAD", &side], &files);
+
+        for incremental in [false, true] {
+            let mut annotator = DiffAnnotator::new(None, None, None, None)
+                .unwrap()
+                .with_incremental(incremental);
+            annotator.rev = merge.clone();
+            assert_eq!(
+                annotator.blame_range("tests/bar.txt", 2, 3).unwrap(),
+                vec![&side[..6], "b40c1d"]
+            );
+            let annotator = annotator.with_first_parent(true);
+            assert_eq!(
+                annotator.blame_range("tests/bar.txt", 2, 3).unwrap(),
+                vec![&merge[..6], "b40c1d"]
+            );
+            // composes with blaming back to a boundary and longer ids
+            let mut annotator = annotator.with_min_abbrev(Some(7));
+            let head = rev_parse("HEAD").unwrap();
+            annotator.boundary = Some(head.clone());
+            assert_eq!(
+                annotator.blame_range("tests/bar.txt", 2, 3).unwrap(),
+                vec![merge[..7].to_string(), format!("^{}", &head[..6])]
+            );
+        }
+    }
+
+    #[test]
+    fn test_show_distance() {
+        // two empty commits on top of HEAD
+        let head = rev_parse("HEAD").unwrap();
+        let first = commit_on_head(&[]);
+        let second = commit_on(&[&first], &[]);
+
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_columns(vec![Column::Distance]);
+        annotator.rev = second.clone();
+        annotator.commits = [&head[..6], &first[..6], &second[..6], "^b40c1", "000000"]
+            .iter()
+            .map(|commit| commit.to_string())
+            .collect();
+        annotator.lookup_distances().unwrap();
+        annotator.widths = annotator.column_widths();
+        let prefixes = [&head, &first, &second].map(|commit| annotator.commit_prefix(&commit[..6]));
+        assert_eq!(prefixes, ["2 ", "1 ", "0 "]);
+        assert_eq!(annotator.marker_prefix('·'), "· ");
+
+        annotator.distances.insert("a1b2c3".to_string(), 12);
+        annotator.commits.push("a1b2c3".to_string());
+        annotator.widths = annotator.column_widths();
+        assert_eq!(annotator.commit_prefix(&head[..6]), " 2 ");
+    }
+
+    #[test]
+    fn test_only_files_changed_by() {
+        // a change to bar.txt only
+        let commit = commit_on_head(&[("100644", "tests/bar.txt", "changed\n")]);
+
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_only_files_changed_by(Some(commit));
+        let mut writer = Vec::new();
+        let mut cwriter = Vec::new();
+        let result = annotator.annotate_diff(Cursor::new(PATCH), &mut writer, &mut cwriter);
+        assert!(result.is_ok());
+        let output = String::from_utf8(writer).unwrap();
+        assert!(output.contains("b40c1d -bar\n++++++ +barbara\n6ec7db  0.5\n"));
+        assert!(output.contains("+++ b/tests/foo.txt\n@@ -1,5 +1,5 @@\n foo\n-bar\n+baz\n"));
+        assert!(output.ends_with("\n 12\n-13\n"));
+    }
+
+    #[test]
+    fn test_skip_extensions() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_skip_extensions(vec!["lock".to_string(), "min.js".to_string()]);
+        for (file, skipped) in [
+            ("Cargo.lock", true),
+            ("src/main.rs", false),
+            ("app.min.js", true),
+            ("app.js", false),
+            ("lock", false),
+        ] {
+            annotator.file = Some(file.to_string());
+            assert_eq!(annotator.is_skipped_file(), skipped, "{file}");
+        }
+
+        let reader = Cursor::new(
+            r"--- a/Cargo.lock
++++ b/Cargo.lock
+@@ -1,1 +1,1 @@
+-foo
++bar
+--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,1 +1,1 @@
+-bar
++barbara
+",
+        );
+        let mut writer = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, io::sink());
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            r"--- a/Cargo.lock
++++ b/Cargo.lock
+@@ -1,1 +1,1 @@
+-foo
++bar
+--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,1 +1,1 @@
+b40c1d -bar
+++++++ +barbara
+"
+        );
+    }
+
+    #[test]
+    fn test_blame_contents() {
+        let path = std::env::temp_dir().join(format!(
+            "blaming-diff-filter-{}.contents",
+            std::process::id()
+        ));
+        let contents =
+            std::fs::read_to_string("tests/bar.txt")
+                .unwrap()
+                .replacen("bar\n", "barbara\n", 1);
+        std::fs::write(&path, contents).unwrap();
+        let annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_contents(Some(path.clone()));
+        let commits = annotator.blame_range("tests/bar.txt", 1, 2);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(commits.unwrap(), vec!["000000", "6ec7db"]);
+    }
+
+    #[test]
+    fn test_make_blame_rev() {
+        let head = rev_parse("HEAD").unwrap();
+        for (back_to, boundary) in [
+            ("HEAD", None),
+            ("HEAD~2", Some("HEAD~2")),
+            ("b40c1d", Some("b40c1d")),
+        ] {
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let run: Runner = Arc::new(counting_runner(&calls));
+            let (rev, sha, base) =
+                DiffAnnotator::make_blame_rev(&run, None, Some(back_to.to_string()), None).unwrap();
+            assert_eq!(rev, "HEAD");
+            assert_eq!(sha, head);
+            assert_eq!(base, boundary.map(|boundary| rev_parse(boundary).unwrap()));
+            // libgit2 resolves without running git
+            let calls = calls.lock().unwrap();
+            let resolved = calls
+                .iter()
+                .filter(|call| *call == "rev-parse HEAD")
+                .count();
+            assert!(resolved <= 1, "{calls:?}");
+        }
+    }
+
+    #[test]
+    fn test_new_with_runner() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let annotator = DiffAnnotator::new_with_runner(
+            None,
+            None,
+            Some("HEAD".to_string()),
+            None,
+            None,
+            counting_runner(&calls),
+        )
+        .unwrap();
+        assert_eq!(annotator.rev_sha, rev_parse("HEAD").unwrap());
+        let calls = calls.lock().unwrap();
+        let resolved = calls
+            .iter()
+            .filter(|call| *call == "rev-parse HEAD")
+            .count();
+        // libgit2 resolves without running git
+        assert_eq!(resolved, usize::from(!cfg!(feature = "git2")), "{calls:?}");
+        assert!(calls.contains(&"config --get core.abbrev".to_string()));
+    }
+
+    #[test]
+    fn test_blame_back_to_ancestor() {
+        let annotator = DiffAnnotator::new(None, Some("HEAD~2".to_string()), None, None).unwrap();
+        assert_eq!(annotator.rev, "HEAD");
+        assert_eq!(annotator.boundary, Some(rev_parse("HEAD~2").unwrap()));
+    }
+
+    #[test]
+    fn test_blame_as_of() {
+        let time = DiffAnnotator::check_output(
+            &runner(),
+            Command::new("git")
+                .arg("show")
+                .arg("-s")
+                .arg("--format=%ct")
+                .arg("b40c1dbc28"),
+        )
+        .unwrap();
+        let annotator = DiffAnnotator::new(None, None, Some(format!("@{time}")), None).unwrap();
+        assert!(annotator.rev.starts_with("b40c1dbc28"));
+        let commits = annotator.blame_range("tests/bar.txt", 1, 3).unwrap();
+        assert_eq!(commits, vec!["b40c1d", "b40c1d", "b40c1d"]);
+    }
+
+    #[test]
+    fn test_annotate_coverage() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_coverage(true)
+            .with_runner(counting_runner(&calls));
+
+        let reader = Cursor::new(
+            r"diff --git a/tests/foo.txt b/tests/foo.txt
+--- a/tests/foo.txt
++++ b/tests/foo.txt
 @@ -1,5 +1,5 @@
  foo
 -bar
@@ -294,45 +6479,263 @@ index 06259808ba40..482e77c74da8 100644
  a
  b
  c
-@@ -7,7 +7,7 @@ d
- +
- -
- +++
--extra
-+wtextra
- bla
- ---
- @@ foo
-@@ -17,7 +17,7 @@ bar
- 3
- 4
- 5
--6
-+5z
- 6a
- 7
- 8
 @@ -25,4 +25,3 @@ bar
  10
  11
  12
 -13
-";
+",
+        );
+        let mut writer = Vec::new();
+        let mut cwriter = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, &mut cwriter);
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(cwriter).unwrap(),
+            "tests/foo.txt: 9/28 lines touched (32%), untouched: 6-24\n"
+        );
+        // counting the lines of the file takes no blame of all of them
+        let calls = calls.lock().unwrap();
+        assert!(
+            calls.contains(&"show HEAD:tests/foo.txt".to_string()),
+            "{calls:?}"
+        );
+        let mut blames = calls.iter().filter(|call| call.contains(" blame "));
+        assert!(blames.all(|call| call.contains(" -L ")), "{calls:?}");
+    }
+
+    #[test]
+    fn test_annotate_wrap() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_wrap(Some(20));
+
+        let reader = Cursor::new(
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,2 @@
+-bar
++barbara-was-here-with-a-long-line
+ 0.5
+",
+        );
+        let mut writer = Vec::new();
+        let mut cwriter = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, &mut cwriter);
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            r"--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,2 @@
+b40c1d -bar
+++++++ +barbara-was-
+       here-with-a-l
+       ong-line
+6ec7db  0.5
+"
+        );
+    }
+
+    #[test]
+    fn test_wrap_width() {
+        let rows = ContextFolder::wrap("+\x1b[31mab\tcdefgh\x1b[0m", 7, 12);
+        assert_eq!(rows, vec!["+\x1b[31mab\tcde", "fgh\x1b[0m"]);
+    }
+
+    #[test]
+    fn test_annotate_side_right() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_side(Side::Right)
+            .with_side_width(Some(24));
+
+        let reader = Cursor::new(
+            "--- a/tests/bar.txt\n+++ b/tests/bar.txt\n@@ -1,2 +1,2 @@\n-bar\n+\tbarbara\n 0.5\n",
+        );
+        let mut writer = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, io::sink());
+        assert!(result.is_ok());
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            "--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,2 @@
+-bar              b40c1d
++\tbarbara   ++++++
+ 0.5              6ec7db
+"
+        );
+    }
+
+    #[test]
+    fn test_annotate_side_inner() {
+        let mut outputs = Vec::new();
+        for side in [Side::Left, Side::Right] {
+            let inner = vec![
+                "tr".to_string(),
+                "[:lower:]".to_string(),
+                "[:upper:]".to_string(),
+            ];
+            let mut annotator = DiffAnnotator::new(Some(inner), None, None, None)
+                .unwrap()
+                .with_side(side)
+                .with_side_width(Some(24));
+            let mut writer = Vec::new();
+            let result = annotator.annotate_diff(Cursor::new(PATCH), &mut writer, io::sink());
+            assert!(result.is_ok());
+            outputs.push(String::from_utf8(writer).unwrap());
+        }
+        let mut annotated = 0;
+        for (left, right) in outputs[0].lines().zip(outputs[1].lines()) {
+            if left == right {
+                continue;
+            }
+            // the same column moves behind the filtered content, padded to the width
+            let (column, content) = left.split_once(' ').unwrap();
+            assert_eq!(right, format!("{content:<18}{column}"));
+            annotated += 1;
+        }
+        assert_eq!(outputs[0].lines().count(), outputs[1].lines().count());
+        assert_eq!(annotated, 39);
+    }
 
     #[test]
-    fn test_parse_hunk() {
-        let mut annotator = DiffAnnotator::new(None, None, None).unwrap();
-        let line = "@@ -36,7 +36,7 @@";
-        let end = annotator.parse_hunk(line);
-        assert_eq!(annotator.start, 36);
-        assert_eq!(end, 43);
+    fn test_annotate_emit_notes() {
+        let path =
+            std::env::temp_dir().join(format!("blaming-diff-filter-{}.notes", std::process::id()));
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_emit_notes(Some(path.clone()));
+
+        let reader = Cursor::new(PATCH);
+        let mut writer = Vec::new();
+        let mut cwriter = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, &mut cwriter);
+        assert!(result.is_ok());
+        let notes = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            notes,
+            r"5aa46cc774fb 2 6ec7db
+5aa46cc774fb 3 b40c1d
+5aa46cc774fb 4 b40c1d
+5aa46cc774fb 5 b40c1d
+5aa46cc774fb 6 6ec7db
+5aa46cc774fb 7 6ec7db
+5aa46cc774fb 10 6ec7db
+482e77c74da8 1 b40c1d
+482e77c74da8 3 b40c1d
+482e77c74da8 4 b40c1d
+482e77c74da8 5 b40c1d
+482e77c74da8 7 b40c1d
+482e77c74da8 8 b40c1d
+482e77c74da8 9 b40c1d
+482e77c74da8 11 b40c1d
+482e77c74da8 12 b40c1d
+482e77c74da8 13 b40c1d
+482e77c74da8 17 b40c1d
+482e77c74da8 18 b40c1d
+482e77c74da8 19 b40c1d
+482e77c74da8 21 6ec7db
+482e77c74da8 22 b40c1d
+482e77c74da8 23 b40c1d
+482e77c74da8 25 b40c1d
+482e77c74da8 26 b40c1d
+482e77c74da8 27 b40c1d
+"
+        );
     }
 
     #[test]
-    fn test_annotate_diff() {
-        let mut annotator = DiffAnnotator::new(None, None, None).unwrap();
+    fn test_abbrev_widens_markers() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_min_abbrev(Some(10));
+        let prefixes: Vec<_> = ["--- a/tests/bar.txt", "@@ -1,1 +1,1 @@", "-bar", "+barbara"]
+            .iter()
+            .filter_map(|line| annotator.annotate_line(line.to_string()).unwrap().prefix)
+            .collect();
+        assert_eq!(prefixes, ["b40c1dbc28 ", "++++++++++ "]);
+    }
 
-        let reader = Cursor::new(PATCH);
+    #[test]
+    fn test_abbrev_width() {
+        for abbrev in [4, 6, 9, 12] {
+            for (back_to, boundary) in [(None, false), (Some("b40c1d".to_string()), true)] {
+                for incremental in [false, true] {
+                    let annotator = DiffAnnotator::new(None, back_to.clone(), None, None)
+                        .unwrap()
+                        .with_min_abbrev(Some(abbrev))
+                        .with_incremental(incremental);
+                    let commits = annotator.blame_range("tests/bar.txt", 3, 3).unwrap();
+                    assert_eq!(commits[0].len(), abbrev);
+                    assert_eq!(commits[0].starts_with('^'), boundary);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_abbrev_full_hash() {
+        let sha = rev_parse("b40c1d").unwrap();
+        assert_eq!(sha.len(), 40);
+        for incremental in [false, true] {
+            let annotator = DiffAnnotator::new(None, None, None, None)
+                .unwrap()
+                .with_min_abbrev(Some(40))
+                .with_incremental(incremental);
+            // git prints one more character than requested with --abbrev, up to the full sha
+            assert_eq!(
+                annotator.blame_range("tests/bar.txt", 3, 3).unwrap(),
+                vec![sha.clone()]
+            );
+        }
+    }
+
+    #[test]
+    fn test_abbrev_clamped() {
+        for (abbrev, len) in [(0, 4), (41, 40)] {
+            let annotator = DiffAnnotator::new(None, None, None, None)
+                .unwrap()
+                .with_min_abbrev(Some(abbrev));
+            let commits = annotator.blame_range("tests/bar.txt", 3, 3).unwrap();
+            assert_eq!(commits[0].len(), len, "{abbrev}");
+        }
+    }
+
+    #[test]
+    fn test_abbrev_exact() {
+        let annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_abbrev(Some(7));
+        // ids git grew to keep them unique are cut back
+        let commits = vec!["0123456789".to_string(), "^abcdef12".to_string()];
+        assert_eq!(annotator.cut_abbrev(commits), ["0123456", "^abcdef"]);
+        let annotator = annotator.with_min_abbrev(Some(7));
+        assert_eq!(
+            annotator.cut_abbrev(vec!["0123456789".to_string()]),
+            ["0123456789"]
+        );
+    }
+
+    #[test]
+    fn test_annotate_min_abbrev() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_min_abbrev(Some(8));
+
+        let reader = Cursor::new(
+            r"diff --git a/tests/bar.txt b/tests/bar.txt
+--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -3,3 +3,3 @@
+ 1
+-2
++two
+ 3
+",
+        );
         let mut writer = Vec::new();
         let mut cwriter = Vec::new();
         let result = annotator.annotate_diff(reader, &mut writer, &mut cwriter);
@@ -340,203 +6743,159 @@ index 06259808ba40..482e77c74da8 100644
         assert_eq!(
             String::from_utf8(writer).unwrap(),
             r"diff --git a/tests/bar.txt b/tests/bar.txt
-index 6d0a9487a999..5aa46cc774fb 10064
 --- a/tests/bar.txt
 +++ b/tests/bar.txt
-@@ -1,10 +1,10 @@
-b40c1d -bar
-++++++ +barbara
-6ec7db  0.5
-b40c1d  1
-b40c1d  2
-b40c1d  3
-6ec7db  foobar
-6ec7db  bar ba baz
-b40c1d -a
-b40c1d -b
-++++++ +A
-++++++ +B
-6ec7db  C
-diff --git a/tests/foo.txt b/tests/foo.txt
-index 06259808ba40..482e77c74da8 100644
---- a/tests/foo.txt
-+++ b/tests/foo.txt
-@@ -1,5 +1,5 @@
-b40c1d  foo
-b40c1d -bar
-++++++ +baz
-b40c1d  a
-b40c1d  b
-b40c1d  c
-@@ -7,7 +7,7 @@ d
-b40c1d  +
-b40c1d  -
-b40c1d  +++
-b40c1d -extra
-++++++ +wtextra
-b40c1d  bla
-b40c1d  ---
-b40c1d  @@ foo
-@@ -17,7 +17,7 @@ bar
-b40c1d  3
-b40c1d  4
-b40c1d  5
-b40c1d -6
-++++++ +5z
-6ec7db  6a
-b40c1d  7
-b40c1d  8
-@@ -25,4 +25,3 @@ bar
-b40c1d  10
-b40c1d  11
-b40c1d  12
-6ec7db -13
+@@ -3,3 +3,3 @@
+b40c1dbc  1
+b40c1dbc -2
+++++++++ +two
+b40c1dbc  3
 "
         );
     }
 
     #[test]
-    fn test_annotate_inner() {
-        let inner = vec![
-            "tr".to_string(),
-            "[:lower:]".to_string(),
-            "[:upper:]".to_string(),
-        ];
-        let format = "%h %s".to_string();
-        let mut annotator = DiffAnnotator::new(Some(inner), None, Some(format)).unwrap();
+    fn test_annotate_cached() {
+        // stage a modification in a temporary index, leaving the real one alone
+        let index = temp_index();
+        let staged = std::fs::read_to_string("tests/bar.txt")
+            .unwrap()
+            .replacen("bar\n", "staged\n", 1);
+        stage_on(&index, "HEAD", &[("100644", "tests/bar.txt", &staged)]);
 
-        let reader = Cursor::new(PATCH);
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_cached(true)
+            .with_index_file(Some(index.clone()))
+            .with_runner(counting_runner(&calls));
+        let reader = Cursor::new(
+            r"diff --git a/tests/bar.txt b/tests/bar.txt
+--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,3 +1,3 @@
+-staged
++bar
+ 0.5
+ 1
+",
+        );
         let mut writer = Vec::new();
         let mut cwriter = Vec::new();
         let result = annotator.annotate_diff(reader, &mut writer, &mut cwriter);
+        std::fs::remove_file(&index).unwrap();
         assert!(result.is_ok());
         assert_eq!(
             String::from_utf8(writer).unwrap(),
-            r"DIFF --GIT A/TESTS/BAR.TXT B/TESTS/BAR.TXT
-INDEX 6D0A9487A999..5AA46CC774FB 10064
---- A/TESTS/BAR.TXT
-+++ B/TESTS/BAR.TXT
-@@ -1,10 +1,10 @@
-b40c1d -BAR
-++++++ +BARBARA
+            r"diff --git a/tests/bar.txt b/tests/bar.txt
+--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,3 +1,3 @@
+······ -staged
+++++++ +bar
 6ec7db  0.5
 b40c1d  1
-b40c1d  2
-b40c1d  3
-6ec7db  FOOBAR
-6ec7db  BAR BA BAZ
-b40c1d -A
-b40c1d -B
-++++++ +A
-++++++ +B
-6ec7db  C
-DIFF --GIT A/TESTS/FOO.TXT B/TESTS/FOO.TXT
-INDEX 06259808BA40..482E77C74DA8 100644
---- A/TESTS/FOO.TXT
-+++ B/TESTS/FOO.TXT
-@@ -1,5 +1,5 @@
-b40c1d  FOO
-b40c1d -BAR
-++++++ +BAZ
-b40c1d  A
-b40c1d  B
-b40c1d  C
-@@ -7,7 +7,7 @@ D
-b40c1d  +
-b40c1d  -
-b40c1d  +++
-b40c1d -EXTRA
-++++++ +WTEXTRA
-b40c1d  BLA
-b40c1d  ---
-b40c1d  @@ FOO
-@@ -17,7 +17,7 @@ BAR
-b40c1d  3
-b40c1d  4
-b40c1d  5
-b40c1d -6
-++++++ +5Z
-6ec7db  6A
-b40c1d  7
-b40c1d  8
-@@ -25,4 +25,3 @@ BAR
-b40c1d  10
-b40c1d  11
-b40c1d  12
-6ec7db -13
 "
         );
-        assert_eq!(
-            String::from_utf8(cwriter).unwrap(),
-            r"b40c1d tests: Add some test data
-6ec7db tests: Add some changes to test files for blame testing
-"
+        // the staged content is read through the runner too
+        let calls = calls.lock().unwrap();
+        assert!(
+            calls.contains(&"show :tests/bar.txt".to_string()),
+            "{calls:?}"
         );
     }
 
     #[test]
-    fn test_annotate_backto() {
-        let mut annotator = DiffAnnotator::new(None, Some("b40c1dbc28".to_string()), None).unwrap();
+    fn test_annotate_fold_context() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_fold_context(Some(3));
 
-        let reader = Cursor::new(PATCH);
+        let reader = Cursor::new(
+            r"diff --git a/tests/foo.txt b/tests/foo.txt
+--- a/tests/foo.txt
++++ b/tests/foo.txt
+@@ -12,14 +12,14 @@
+ ---
+ @@ foo
+ bar
+ 1
+ 2
+ 3
+ 4
+-5
++5z
+ 6
+ 6a
+ 7
+ 8
+ 9
+ 10
+",
+        );
         let mut writer = Vec::new();
         let mut cwriter = Vec::new();
         let result = annotator.annotate_diff(reader, &mut writer, &mut cwriter);
         assert!(result.is_ok());
         assert_eq!(
             String::from_utf8(writer).unwrap(),
-            r"diff --git a/tests/bar.txt b/tests/bar.txt
-index 6d0a9487a999..5aa46cc774fb 10064
---- a/tests/bar.txt
-+++ b/tests/bar.txt
-@@ -1,10 +1,10 @@
-······ -bar
-++++++ +barbara
-6ec7db  0.5
-······  1
-······  2
-······  3
-6ec7db  foobar
-6ec7db  bar ba baz
-······ -a
-······ -b
-++++++ +A
-++++++ +B
-6ec7db  C
-diff --git a/tests/foo.txt b/tests/foo.txt
-index 06259808ba40..482e77c74da8 100644
+            r"diff --git a/tests/foo.txt b/tests/foo.txt
 --- a/tests/foo.txt
 +++ b/tests/foo.txt
-@@ -1,5 +1,5 @@
-······  foo
-······ -bar
-++++++ +baz
-······  a
-······  b
-······  c
-@@ -7,7 +7,7 @@ d
-······  +
-······  -
-······  +++
-······ -extra
-++++++ +wtextra
-······  bla
-······  ---
-······  @@ foo
-@@ -17,7 +17,7 @@ bar
-······  3
-······  4
-······  5
-······ -6
+@@ -12,14 +12,14 @@
+b40c1d  ---
+b40c1d  @@ foo
+        … 4 lines from b40c1d …
+b40c1d  4
+b40c1d -5
 ++++++ +5z
+b40c1d  6
 6ec7db  6a
-······  7
-······  8
-@@ -25,4 +25,3 @@ bar
-······  10
-······  11
-······  12
-6ec7db -13
+b40c1d  7
+b40c1d  8
+b40c1d  9
+b40c1d  10
+"
+        );
+    }
+
+    #[test]
+    fn test_annotate_fold_context_changed_column() {
+        let mut annotator = DiffAnnotator::new(None, None, None, None)
+            .unwrap()
+            .with_fold_context(Some(2))
+            .with_context_column(ContextColumn::Changed);
+
+        let reader = Cursor::new(
+            r"--- a/tests/foo.txt
++++ b/tests/foo.txt
+@@ -19,7 +19,7 @@
+-5
++5z
+ 6
+ 6a
+ 7
+ 8
+ 9
+ 10
+",
+        );
+        let mut writer = Vec::new();
+        let result = annotator.annotate_diff(reader, &mut writer, io::sink());
+        assert!(result.is_ok());
+        // the blank column of 6a must not join it to the runs of b40c1d
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            "--- a/tests/foo.txt
++++ b/tests/foo.txt
+@@ -19,7 +19,7 @@
+b40c1d -5
+++++++ +5z
+        6
+        6a
+        7
+        … 2 lines from b40c1d …
+        10
 "
         );
     }
@@ -0,0 +1,289 @@
+use clap::parser::ValueSource;
+use clap::{ArgAction, Command};
+use std::ffi::OsString;
+use std::io;
+
+/// A value in a config file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// `true` or `false`.
+    Boolean(bool),
+    /// A decimal integer, such as `7`.
+    Integer(i64),
+    /// A basic `"..."` or literal `'...'` string.
+    String(String),
+    /// A single-line array of values, such as `["hash", "author"]`.
+    Array(Vec<Value>),
+}
+
+/// Parse a config file.
+///
+/// * `toml` - The content, a subset of TOML with top-level `key = value` pairs of booleans,
+///   integers, strings and single-line arrays, and comments.
+///
+/// Returns the keys with their values, in order.
+pub fn parse(toml: &str) -> io::Result<Vec<(String, Value)>> {
+    let mut pairs = Vec::new();
+    for (i, line) in toml.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {reason}", i + 1),
+            )
+        };
+        if line.starts_with('[') {
+            return Err(error("tables are not supported"));
+        }
+        let (key, mut rest) = line
+            .split_once('=')
+            .ok_or_else(|| error("expected key = value"))?;
+        let key = key.trim();
+        let bare = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+        if key.is_empty() || !key.chars().all(bare) {
+            return Err(error("expected a bare key"));
+        }
+        let value = parse_value(&mut rest).map_err(error)?;
+        let rest = rest.trim_start();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err(error("unexpected text after value"));
+        }
+        pairs.push((key.to_string(), value));
+    }
+    Ok(pairs)
+}
+
+fn parse_value(input: &mut &str) -> Result<Value, &'static str> {
+    *input = input.trim_start();
+    if let Some(rest) = input.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    *input = &rest[i + 1..];
+                    return Ok(Value::String(value));
+                }
+                '\\' => value.push(match chars.next() {
+                    Some((_, 'n')) => '\n',
+                    Some((_, 't')) => '\t',
+                    Some((_, 'r')) => '\r',
+                    Some((_, '"')) => '"',
+                    Some((_, '\\')) => '\\',
+                    _ => return Err("unsupported escape in string"),
+                }),
+                c => value.push(c),
+            }
+        }
+        Err("unterminated string")
+    } else if let Some(rest) = input.strip_prefix('\'') {
+        let (value, rest) = rest.split_once('\'').ok_or("unterminated string")?;
+        *input = rest;
+        Ok(Value::String(value.to_string()))
+    } else if let Some(rest) = input.strip_prefix('[') {
+        let mut values = Vec::new();
+        *input = rest.trim_start();
+        // trailing commas are fine, as in TOML
+        while !input.starts_with(']') {
+            values.push(parse_value(input)?);
+            *input = input.trim_start();
+            match input.strip_prefix(',') {
+                Some(rest) => *input = rest.trim_start(),
+                None if input.starts_with(']') => (),
+                None => return Err("expected , or ] in array"),
+            }
+        }
+        *input = &input[1..];
+        Ok(Value::Array(values))
+    } else {
+        let end = input
+            .find(|c: char| c.is_whitespace() || c == ',' || c == ']' || c == '#')
+            .unwrap_or(input.len());
+        let value = match &input[..end] {
+            "true" => Value::Boolean(true),
+            "false" => Value::Boolean(false),
+            word => Value::Integer(word.parse().map_err(|_| "expected a value")?),
+        };
+        *input = &input[end..];
+        Ok(value)
+    }
+}
+
+/// Merge config defaults under command line arguments.
+///
+/// * `cmd` - The command the arguments are for; keys name its long options, or aliases, and
+///   positional arguments.
+/// * `args` - The command line arguments, starting with the program name.
+/// * `config` - The keys and values of the config.
+///
+/// Returns the arguments with the config values added for any argument not given on the
+/// command line.
+pub fn merge(
+    cmd: &Command,
+    args: Vec<OsString>,
+    config: &[(String, Value)],
+) -> io::Result<Vec<OsString>> {
+    let matches = cmd
+        .clone()
+        .try_get_matches_from(&args)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let mut options = Vec::new();
+    let mut positionals = Vec::new();
+    for (key, value) in config {
+        let arg = cmd
+            .get_arguments()
+            .find(|arg| match arg.get_long() {
+                Some(long) => {
+                    long == key
+                        || arg
+                            .get_all_aliases()
+                            .into_iter()
+                            .flatten()
+                            .any(|a| a == key)
+                }
+                None => arg.is_positional() && arg.get_id() == key.as_str(),
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown config key {key}"),
+                )
+            })?;
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let values = match value {
+            Value::Array(values) => values.clone(),
+            value => vec![value.clone()],
+        };
+        for value in values {
+            let value = match value {
+                Value::Boolean(set) if matches!(arg.get_action(), ArgAction::SetTrue) => {
+                    if set {
+                        options.push(format!("--{}", arg.get_long().unwrap()).into());
+                    }
+                    continue;
+                }
+                Value::Boolean(value) => value.to_string(),
+                Value::Integer(value) => value.to_string(),
+                Value::String(value) => value,
+                Value::Array(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("nested array for config key {key}"),
+                    ))
+                }
+            };
+            match arg.get_long() {
+                Some(long) => options.push(format!("--{long}={value}").into()),
+                None => positionals.push(value.into()),
+            }
+        }
+    }
+    let mut args = args.into_iter();
+    let mut merged: Vec<OsString> = args.next().into_iter().chain(options).collect();
+    merged.extend(args);
+    if !positionals.is_empty() {
+        merged.push("--".into());
+        merged.extend(positionals);
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, Parser};
+
+    #[derive(Parser, Debug)]
+    struct Args {
+        #[arg(short, long)]
+        format: Option<String>,
+        #[arg(long, visible_alias = "abbrev")]
+        min_abbrev: Option<u8>,
+        #[arg(long)]
+        stat: bool,
+        #[arg(long, value_delimiter = ',')]
+        columns: Vec<String>,
+        inner: Option<Vec<String>>,
+    }
+
+    const CONFIG: &str = r#"
+# defaults for blaming-diff-filter
+inner = ["delta", "--color-only"]
+format = "%h %s"  # one line per candidate
+abbrev = 8
+stat = true
+columns = ['hash', "author",]
+"#;
+
+    fn parse_args(cli: &[&str]) -> Args {
+        let config = parse(CONFIG).unwrap();
+        let args = std::iter::once("bdf").chain(cli.iter().copied());
+        let merged = merge(
+            &Args::command(),
+            args.map(OsString::from).collect(),
+            &config,
+        );
+        Args::parse_from(merged.unwrap())
+    }
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!(
+            parse(CONFIG).unwrap(),
+            [
+                (
+                    "inner".to_string(),
+                    Value::Array(vec![
+                        Value::String("delta".to_string()),
+                        Value::String("--color-only".to_string())
+                    ])
+                ),
+                ("format".to_string(), Value::String("%h %s".to_string())),
+                ("abbrev".to_string(), Value::Integer(8)),
+                ("stat".to_string(), Value::Boolean(true)),
+                (
+                    "columns".to_string(),
+                    Value::Array(vec![
+                        Value::String("hash".to_string()),
+                        Value::String("author".to_string())
+                    ])
+                ),
+            ]
+        );
+        for (toml, error) in [
+            ("[section]", "line 1: tables are not supported"),
+            ("format", "line 1: expected key = value"),
+            ("format = \"%h", "line 1: unterminated string"),
+            ("\n\nabbrev = 8 9", "line 3: unexpected text after value"),
+            ("abbrev = eight", "line 1: expected a value"),
+        ] {
+            assert_eq!(parse(toml).unwrap_err().to_string(), error);
+        }
+    }
+
+    #[test]
+    fn test_merge_config() {
+        let args = parse_args(&[]);
+        assert_eq!(args.format.as_deref(), Some("%h %s"));
+        assert_eq!(args.min_abbrev, Some(8));
+        assert!(args.stat);
+        assert_eq!(args.columns, ["hash", "author"]);
+        assert_eq!(args.inner.unwrap(), ["delta", "--color-only"]);
+
+        let args = parse_args(&["--format=%H", "--min-abbrev", "10", "--columns=date", "cat"]);
+        assert_eq!(args.format.as_deref(), Some("%H"));
+        assert_eq!(args.min_abbrev, Some(10));
+        assert_eq!(args.columns, ["date"]);
+        assert_eq!(args.inner.unwrap(), ["cat"]);
+
+        let config = parse("color = true").unwrap();
+        let args = vec![OsString::from("bdf")];
+        let err = merge(&Args::command(), args, &config).unwrap_err();
+        assert_eq!(err.to_string(), "unknown config key color");
+    }
+}
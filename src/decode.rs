@@ -0,0 +1,186 @@
+use clap::ValueEnum;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+
+/// Text encoding of an input diff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Encoding {
+    /// Standard base64, line breaks and other whitespace are ignored.
+    Base64,
+}
+
+/// Compression of an input diff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    /// gzip, as decompressed by `gzip -dc`.
+    Gzip,
+}
+
+/// Read a diff transported encoded or compressed.
+///
+/// * `reader` - A reader for the raw input.
+/// * `encoding` - An optional encoding to decode first.
+/// * `compression` - An optional compression to decompress after decoding.
+///
+/// Returns the plain diff.
+pub fn decode<R: Read>(
+    mut reader: R,
+    encoding: Option<Encoding>,
+    compression: Option<Compression>,
+) -> io::Result<Vec<u8>> {
+    let mut input = Vec::new();
+    reader.read_to_end(&mut input)?;
+    if let Some(Encoding::Base64) = encoding {
+        input = base64(&input)?;
+    }
+    if let Some(Compression::Gzip) = compression {
+        input = gunzip(&input)?;
+    }
+    Ok(input)
+}
+
+fn base64(input: &[u8]) -> io::Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len() / 4 * 3);
+    let (mut acc, mut bits) = (0u32, 0);
+    let mut padding = 0;
+    for &b in input.iter().filter(|b| !b.is_ascii_whitespace()) {
+        let value = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => {
+                padding += 1;
+                continue;
+            }
+            _ => return Err(invalid_base64(format!("unexpected byte {:?}", b as char))),
+        };
+        if padding > 0 {
+            return Err(invalid_base64("data after padding".to_string()));
+        }
+        acc = acc << 6 | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((acc >> bits) as u8);
+        }
+    }
+    // leftover bits of a final partial group are zero fill, a single leftover sextet is not
+    if bits >= 6 || padding > 2 {
+        return Err(invalid_base64("truncated input".to_string()));
+    }
+    Ok(output)
+}
+
+fn invalid_base64(reason: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid base64: {reason}"),
+    )
+}
+
+fn gunzip(input: &[u8]) -> io::Result<Vec<u8>> {
+    let mut child = Command::new("gzip")
+        .arg("-dc")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), "gzip -dc"))?;
+    let mut stdin = child.stdin.take().unwrap();
+    // feed the input from a thread, as gzip blocks writing once the output pipe is full
+    let output = std::thread::scope(|scope| {
+        let feeder = scope.spawn(move || stdin.write_all(input));
+        let output = child.wait_with_output();
+        // gzip may quit reading early on corrupt input, its status tells why
+        let _ = feeder.join();
+        output
+    })?;
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "gzip -dc: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotate::DiffAnnotator;
+
+    const PATCH: &str = "--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,3 +1,3 @@
+-bar
++barbara
+ 0.5
+ 1
+";
+
+    fn filter(cmd: &str, input: &[u8]) -> Vec<u8> {
+        let mut child = Command::new(cmd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        child.wait_with_output().unwrap().stdout
+    }
+
+    fn annotate(input: &[u8]) -> String {
+        let mut annotator = DiffAnnotator::new(None, None, None, None).unwrap();
+        let mut writer = Vec::new();
+        annotator
+            .annotate_diff(input, &mut writer, io::sink())
+            .unwrap();
+        String::from_utf8(writer).unwrap()
+    }
+
+    #[test]
+    fn test_decode_base64() {
+        for (encoded, decoded) in [
+            ("", ""),
+            ("Zg==", "f"),
+            ("Zm8=", "fo"),
+            ("Zm9v", "foo"),
+            ("Zm9v\nYmFy\n", "foobar"),
+            ("Zm9vYg", "foob"),
+        ] {
+            let output = decode(encoded.as_bytes(), Some(Encoding::Base64), None).unwrap();
+            assert_eq!(output, decoded.as_bytes());
+        }
+        for encoded in ["Zm9v!", "Zg==Zg==", "Z", "Zm9vY"] {
+            let err = decode(encoded.as_bytes(), Some(Encoding::Base64), None).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_decode_gzip() {
+        let err = decode(&b"not gzipped"[..], None, Some(Compression::Gzip)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_annotate_decoded() {
+        let plain = annotate(PATCH.as_bytes());
+        let base64 = filter("base64", PATCH.as_bytes());
+        let gzip = filter("gzip", PATCH.as_bytes());
+        let both = filter("base64", &gzip);
+        for (input, encoding, compression) in [
+            (base64, Some(Encoding::Base64), None),
+            (gzip, None, Some(Compression::Gzip)),
+            (both, Some(Encoding::Base64), Some(Compression::Gzip)),
+        ] {
+            let decoded = decode(&input[..], encoding, compression).unwrap();
+            assert_eq!(annotate(&decoded), plain);
+        }
+    }
+}
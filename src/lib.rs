@@ -1,3 +1,6 @@
 //! Annotate `git-diff` lines with originating commit-id.
 #![doc = include_str!("../README.md")]
 pub mod annotate;
+pub mod config;
+pub mod decode;
+pub mod pager;
@@ -1,23 +1,537 @@
-use blaming_diff_filter::annotate::DiffAnnotator;
-use clap::{command, Parser};
-use std::io;
+use blaming_diff_filter::annotate::{
+    CandidateDate, CandidateSort, ColorBy, Column, ContextColumn, DiffAnnotator, Side, Source,
+    Theme,
+};
+use blaming_diff_filter::config;
+use blaming_diff_filter::decode::{self, Compression, Encoding};
+use blaming_diff_filter::pager::Pager;
+use clap::{ColorChoice, CommandFactory, Parser};
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
 
 /// git diffFilter annotating each line with originating commit-id.
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Read the diff from a file instead of stdin, or from stdin with -.
+    #[arg(long, value_name = "file")]
+    input: Option<PathBuf>,
+    /// Read defaults for options from a TOML file, keyed by long option names.
+    #[arg(long, value_name = "file")]
+    config: Option<PathBuf>,
+    /// Run git in another repository, as with git -C.
+    #[arg(short = 'C', long, value_name = "path")]
+    git_dir: Option<PathBuf>,
     /// Blame up to common ancestor.
     #[arg(short, long, value_name = "commitid")]
     back_to: Option<String>,
+    /// Blame the commit that was HEAD at a date.
+    #[arg(long, value_name = "date", conflicts_with = "cached")]
+    as_of: Option<String>,
     /// Print candidates using git `format-string`.
     #[arg(short, long, value_name = "format-string")]
     format: Option<String>,
+    /// Write candidates and reports to a file instead of stderr.
+    #[arg(long, value_name = "file")]
+    candidates_file: Option<PathBuf>,
+    /// Collapse fixup!/squash! candidates into their target.
+    #[arg(long, requires = "format")]
+    squash_candidates: bool,
+    /// Order of printed candidates.
+    #[arg(
+        long,
+        value_name = "order",
+        visible_alias = "sort",
+        default_value = "date",
+        requires = "format"
+    )]
+    candidate_sort: CandidateSort,
+    /// Blame the staged state instead of HEAD.
+    #[arg(long)]
+    cached: bool,
+    /// Blame the HEAD, index or worktree content of files, index being the same as --cached.
+    #[arg(long, value_name = "source", conflicts_with_all = ["cached", "contents", "as_of"])]
+    source: Option<Source>,
+    /// Blame against the content of a file.
+    #[arg(long, value_name = "file", conflicts_with_all = ["cached", "as_of"])]
+    contents: Option<PathBuf>,
+    /// Expect paths without a/ and b/ prefixes, as from git diff --no-prefix.
+    #[arg(long, conflicts_with_all = ["src_prefix", "dst_prefix"])]
+    no_prefix: bool,
+    /// Expect old paths with a prefix, as from git diff --src-prefix.
+    #[arg(long, value_name = "prefix")]
+    src_prefix: Option<String>,
+    /// Expect new paths with a prefix, as from git diff --dst-prefix.
+    #[arg(long, value_name = "prefix")]
+    dst_prefix: Option<String>,
+    /// Fail if files of the diff have uncommitted changes.
+    #[arg(long)]
+    require_clean_tree: bool,
+    /// Blame the result of applying the diff.
+    #[arg(long, conflicts_with_all = ["cached", "contents"])]
+    post_apply: bool,
+    /// Ignore revs read from stdin in blame, with the diff read from --input.
+    #[arg(long, requires = "input")]
+    ignore_revs_stdin: bool,
+    /// Ignore revs listed in a file in blame, defaults to blame.ignoreRevsFile.
+    #[arg(long, value_name = "file")]
+    ignore_revs_file: Option<PathBuf>,
+    /// Ignore whitespace changes in blame.
+    #[arg(short = 'w', long)]
+    ignore_whitespace: bool,
+    /// Follow only the first parent of merges in blame.
+    #[arg(long)]
+    first_parent: bool,
+    /// Blame all hunks of a file with a single git blame.
+    #[arg(long)]
+    batch_blame_ranges: bool,
+    /// Blame up to n files concurrently with --batch-blame-ranges, defaults to the CPU count.
+    #[arg(long, value_name = "n", requires = "batch_blame_ranges")]
+    parallel_files: Option<usize>,
+    /// Reuse blames of whole files across runs, kept in a file.
+    #[arg(long, value_name = "file", conflicts_with_all = ["cached", "contents", "post_apply", "batch_blame_ranges"])]
+    blame_cache_file: Option<PathBuf>,
+    /// Use incremental blame output.
+    #[arg(long)]
+    incremental: bool,
+    /// Minimum length of commit-ids, defaults to core.abbrev or 6.
+    #[arg(long, value_name = "n", value_parser = clap::value_parser!(u8).range(4..=40))]
+    min_abbrev: Option<u8>,
+    /// Exact length of commit-ids, even where they are ambiguous.
+    #[arg(long, value_name = "n", conflicts_with = "min_abbrev", value_parser = clap::value_parser!(u8).range(4..=40))]
+    abbrev: Option<u8>,
+    /// Show full commit-ids, as --min-abbrev 40.
+    #[arg(long, conflicts_with_all = ["min_abbrev", "abbrev"])]
+    full_hash: bool,
+    /// Append the number of distinct authors to hunk headers.
+    #[arg(long)]
+    hunk_authors: bool,
+    /// Append the commit of the function context to hunk headers.
+    #[arg(long)]
+    blame_header_context: bool,
+    /// Collapse hunks only changing whitespace to a summary line.
+    #[arg(long)]
+    squash_whitespace_only_hunks: bool,
+    /// Warn about lines blame could not annotate.
+    #[arg(long)]
+    verify: bool,
+    /// Print debug messages, such as about lines blame could not annotate.
+    #[arg(long)]
+    debug: bool,
+    /// Report the commits of removed lines with counts per file.
+    #[arg(long)]
+    file_report: bool,
+    /// Print only the number of distinct candidate commits.
+    #[arg(long, conflicts_with_all = ["json", "inner"])]
+    count_only: bool,
+    /// Summarize blamed lines, commits and files.
+    #[arg(long)]
+    stat: bool,
+    /// Summary format with {lines}, {commits} and {files} placeholders.
+    #[arg(long, value_name = "format", requires = "stat")]
+    summary_format: Option<String>,
+    /// Show the author date of candidates, short by default.
+    #[arg(
+        long,
+        value_name = "format",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "short",
+        requires = "format"
+    )]
+    candidate_date: Option<CandidateDate>,
+    /// Print at most n candidates, summarizing the rest.
+    #[arg(long, value_name = "n", requires = "format")]
+    max_candidates: Option<usize>,
+    /// Flag changed lines older than a duration, such as 90d, 6w or 2y.
+    #[arg(long, value_name = "duration", value_parser = parse_duration)]
+    max_candidate_age: Option<u64>,
+    /// Line separating concatenated diffs, listing candidates per diff.
+    #[arg(long, value_name = "line")]
+    record_separator: Option<String>,
+    /// Annotate only files touched by a commit.
+    #[arg(long, value_name = "commitid")]
+    only_files_changed_by: Option<String>,
+    /// Pass through files with an extension, such as lock or min.js, unannotated.
+    #[arg(long, value_name = "ext")]
+    skip_extension: Vec<String>,
+    /// Number context and added lines by their line in the new file.
+    #[arg(long, conflicts_with = "fold_context")]
+    ref_numbers: bool,
+    /// Annotate each commit only where it first appears in the diff.
+    #[arg(long)]
+    first_only: bool,
+    /// Check that the diff can be annotated, without running git.
+    #[arg(long)]
+    parse_only: bool,
+    /// Write annotated lines grouped by file as JSON, as --output=json.
+    #[arg(long, conflicts_with_all = ["inner", "fold_context", "wrap", "output"])]
+    json: bool,
+    /// Format of the output, json writes annotated lines grouped by file and the candidates.
+    #[arg(long, value_name = "format", default_value = "text")]
+    output: OutputFormat,
+    /// Report lines of changed files left untouched.
+    #[arg(long)]
+    coverage: bool,
+    /// Fold same-commit context runs longer than n lines.
+    #[arg(long, value_name = "n")]
+    fold_context: Option<usize>,
+    /// Annotate with several columns, such as hash,author,date.
+    #[arg(long, value_name = "columns", value_delimiter = ',')]
+    columns: Vec<Column>,
+    /// Annotate context lines always, with a blank column for changed only, or never.
+    #[arg(long, value_name = "when", default_value = "always")]
+    context_column: ContextColumn,
+    /// Annotate with the commit subject, truncated to a width, as subject[=N], the age, or
+    /// the number of commits since.
+    #[arg(
+        long,
+        value_name = "subject[=N]|age|distance",
+        value_parser = parse_show,
+        conflicts_with = "columns"
+    )]
+    show: Option<Show>,
+    /// Annotate with the author date instead of commit-ids, as short or relative.
+    #[arg(
+        long,
+        value_name = "format",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "short",
+        conflicts_with_all = ["columns", "show", "show_author"]
+    )]
+    show_date: Option<DateFormat>,
+    /// Annotate with author initials instead of commit-ids.
+    #[arg(long, conflicts_with_all = ["columns", "show"])]
+    show_author: bool,
+    /// When to color commit-ids and candidates; auto colors on terminals unless NO_COLOR is set,
+    /// NO_COLOR does not affect always.
+    #[arg(long, value_name = "when", default_value = "auto")]
+    color: ColorChoice,
+    /// Color commit-ids by commit, author or age.
+    #[arg(long, value_name = "property")]
+    color_by: Option<ColorBy>,
+    /// Color commit-ids by commit, as --color-by commit.
+    #[arg(long, conflicts_with = "color_by")]
+    color_commits: bool,
+    /// Color commits by their full sha, the same across runs and machines.
+    #[arg(long)]
+    stable_colors: bool,
+    /// Link commit-ids to a URL on terminals, with {commit} replaced by the full sha.
+    #[arg(long, value_name = "template")]
+    link_url: Option<String>,
+    /// Character for lines from before the --back-to ancestor or not committed yet.
+    #[arg(long, value_name = "char", default_value = "·", value_parser = parse_marker)]
+    ancestor_char: char,
+    /// Character for lines that could not be blamed.
+    #[arg(long, value_name = "char", default_value = "?", value_parser = parse_marker)]
+    unknown_char: char,
+    /// Character for added lines.
+    #[arg(long, value_name = "char", default_value = "+", value_parser = parse_marker)]
+    added_char: char,
+    /// Color palette for colored commit-ids.
+    #[arg(long, value_name = "name", default_value = "dark")]
+    theme: Theme,
+    /// Write per-line provenance of context lines to a file.
+    #[arg(long, value_name = "file")]
+    emit_notes: Option<PathBuf>,
+    /// Soft-wrap annotated lines at a width.
+    #[arg(long, value_name = "width")]
+    wrap: Option<usize>,
+    /// Put the annotation column left or right of the content.
+    #[arg(
+        long,
+        value_name = "side",
+        visible_alias = "annotate-position",
+        default_value = "left",
+        conflicts_with = "wrap"
+    )]
+    side: Side,
+    /// Width to pad lines to with the column on the right, defaults to $COLUMNS or 80.
+    #[arg(long, value_name = "width")]
+    side_width: Option<usize>,
+    /// Decode the input diff from an encoding.
+    #[arg(long, value_name = "encoding")]
+    decode: Option<Encoding>,
+    /// Decompress the input diff, after decoding.
+    #[arg(long, value_name = "format")]
+    decompress: Option<Compression>,
+    /// Page output if on a terminal, using $PAGER or less.
+    #[arg(long, value_name = "cmd", num_args = 0..=1, require_equals = true)]
+    pager: Option<Option<String>>,
+    /// Reference time for ages, in seconds since the epoch.
+    #[arg(long, value_name = "epoch", hide = true)]
+    now: Option<u64>,
     /// Inner diff filter to run.
     inner: Option<Vec<String>>,
 }
 
+/// Format of dates shown instead of commit-ids.
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum DateFormat {
+    /// The date as YYYY-MM-DD.
+    Short,
+    /// The age, such as 3 weeks ago.
+    Relative,
+}
+
+/// Format of the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    /// The diff with annotated lines.
+    Text,
+    /// A JSON document of annotated lines and candidates.
+    Json,
+}
+
+/// What to show instead of commit-ids.
+#[derive(Debug, Clone, Copy)]
+enum Show {
+    /// The subject with an optional width.
+    Subject(Option<usize>),
+    /// The compact age.
+    Age,
+    /// The number of commits since.
+    Distance,
+}
+
+fn parse_show(show: &str) -> Result<Show, String> {
+    match show.split_once('=') {
+        Some(("subject", width)) => match width.parse() {
+            Ok(0) => Err("subject width must be at least 1".to_string()),
+            Ok(width) => Ok(Show::Subject(Some(width))),
+            Err(e) => Err(format!("{e}")),
+        },
+        None if show == "subject" => Ok(Show::Subject(None)),
+        None if show == "age" => Ok(Show::Age),
+        None if show == "distance" => Ok(Show::Distance),
+        _ => Err("expected subject, subject=N, age or distance".to_string()),
+    }
+}
+
+fn parse_duration(duration: &str) -> Result<u64, String> {
+    const DAY: u64 = 24 * 60 * 60;
+    let (count, unit) = match duration.char_indices().last() {
+        Some((i, unit)) => (&duration[..i], unit),
+        None => ("", ' '),
+    };
+    let unit = match unit {
+        'd' => DAY,
+        'w' => 7 * DAY,
+        'y' => 365 * DAY,
+        _ => return Err("expected a number of days, weeks or years, such as 90d".to_string()),
+    };
+    let count = count.parse::<u64>().map_err(|e| format!("{e}"))?;
+    count
+        .checked_mul(unit)
+        .ok_or_else(|| "duration too large".to_string())
+}
+
+fn parse_marker(marker: &str) -> Result<char, String> {
+    let mut chars = marker.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if !c.is_control() && !is_wide_or_zero_width(c) => Ok(c),
+        _ => Err("expected a single character one column wide".to_string()),
+    }
+}
+
+fn is_wide_or_zero_width(c: char) -> bool {
+    // the common combining, zero width and East Asian wide or emoji blocks
+    matches!(
+        c,
+        '\u{300}'..='\u{36f}'
+            | '\u{200b}'..='\u{200f}'
+            | '\u{1100}'..='\u{115f}'
+            | '\u{2e80}'..='\u{a4cf}'
+            | '\u{ac00}'..='\u{d7a3}'
+            | '\u{f900}'..='\u{faff}'
+            | '\u{fe30}'..='\u{fe4f}'
+            | '\u{ff00}'..='\u{ff60}'
+            | '\u{ffe0}'..='\u{ffe6}'
+            | '\u{1f300}'..='\u{1f64f}'
+            | '\u{1f900}'..='\u{1f9ff}'
+            | '\u{20000}'..='\u{3fffd}'
+    )
+}
+
 fn main() -> io::Result<()> {
-    let args = Args::parse();
-    let mut annotator = DiffAnnotator::new(args.inner, args.back_to, args.format)?;
-    annotator.annotate_diff(io::stdin().lock(), io::stdout(), io::stderr())
+    let mut args = Args::parse();
+    if let Some(path) = &args.config {
+        let config = std::fs::read_to_string(path)
+            .and_then(|toml| config::parse(&toml))
+            .and_then(|config| {
+                config::merge(&Args::command(), std::env::args_os().collect(), &config)
+            })
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+        args = Args::parse_from(config);
+    }
+    if args.output == OutputFormat::Json {
+        let conflicts = [
+            ("--inner", args.inner.is_some()),
+            ("--fold-context", args.fold_context.is_some()),
+            ("--wrap", args.wrap.is_some()),
+            ("--count-only", args.count_only),
+        ];
+        if let Some((arg, _)) = conflicts.iter().find(|(_, given)| *given) {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    format!("--output=json cannot be used with {arg}"),
+                )
+                .exit();
+        }
+        args.json = true;
+    }
+    let diff_stdin = args
+        .input
+        .as_ref()
+        .is_none_or(|path| path.as_os_str() == "-");
+    if args.ignore_revs_stdin && diff_stdin {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--ignore-revs-stdin cannot be used with the diff read from stdin",
+            )
+            .exit();
+    }
+    let mut reader: Box<dyn io::BufRead> = match &args.input {
+        Some(path) if path.as_os_str() != "-" => {
+            let file = std::fs::File::open(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+            Box::new(io::BufReader::new(file))
+        }
+        _ => Box::new(io::stdin().lock()),
+    };
+    if args.decode.is_some() || args.decompress.is_some() {
+        let input = decode::decode(reader, args.decode, args.decompress)?;
+        reader = Box::new(io::Cursor::new(input));
+    }
+    if args.parse_only {
+        let problems = DiffAnnotator::parse_only(reader, io::stderr())?;
+        std::process::exit(if problems == 0 { 0 } else { 1 });
+    }
+    let ignore_revs = match args.ignore_revs_stdin {
+        // same format as blame.ignoreRevsFile, with comments and blank lines
+        true => io::read_to_string(io::stdin())?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect(),
+        false => Vec::new(),
+    };
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let color = |terminal: bool| match args.color {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => terminal && !no_color,
+    };
+    let gutter_color = color(io::stdout().is_terminal());
+    let candidate_color = color(args.candidates_file.is_none() && io::stderr().is_terminal());
+    let mut annotator = DiffAnnotator::new_in(
+        args.git_dir,
+        args.inner,
+        args.back_to,
+        args.as_of,
+        args.format,
+    )?
+    .with_fold_context(args.fold_context)
+    .with_cached(args.cached)
+    .with_source(args.source)
+    .with_min_abbrev(match args.full_hash {
+        true => Some(40),
+        false => args.min_abbrev.map(usize::from),
+    })
+    .with_abbrev(args.abbrev.map(usize::from))
+    .with_coverage(args.coverage)
+    .with_squash_candidates(args.squash_candidates)
+    .with_color_by(
+        args.color_by
+            .or(args.color_commits.then_some(ColorBy::Commit))
+            .filter(|_| gutter_color),
+    )
+    .with_candidate_color(candidate_color)
+    .with_emit_notes(args.emit_notes)
+    .with_wrap(args.wrap)
+    .with_contents(args.contents)
+    .with_now(args.now)
+    .with_hunk_authors(args.hunk_authors)
+    .with_blame_header_context(args.blame_header_context)
+    .with_theme(args.theme)
+    .with_incremental(args.incremental)
+    .with_squash_whitespace_only_hunks(args.squash_whitespace_only_hunks)
+    .with_post_apply(args.post_apply)
+    .with_require_clean_tree(args.require_clean_tree)
+    .with_no_prefix(args.no_prefix)
+    .with_prefixes(args.src_prefix, args.dst_prefix)
+    .with_verify(args.verify)
+    .with_debug(args.debug)
+    .with_columns(match args.show {
+        Some(Show::Subject(_)) => vec![Column::Subject],
+        Some(Show::Age) => vec![Column::Age],
+        Some(Show::Distance) => vec![Column::Distance],
+        _ if args.show_author => vec![Column::Initials],
+        _ => match args.show_date {
+            Some(DateFormat::Short) => vec![Column::Date],
+            Some(DateFormat::Relative) => vec![Column::RelativeDate],
+            None => args.columns,
+        },
+    })
+    .with_subject_width(match args.show {
+        Some(Show::Subject(width)) => width,
+        _ => None,
+    })
+    .with_ignore_revs(ignore_revs)
+    .with_ignore_revs_file(args.ignore_revs_file)
+    .with_ignore_whitespace(args.ignore_whitespace)
+    .with_first_parent(args.first_parent)
+    .with_stat(args.stat)
+    .with_count_only(args.count_only)
+    .with_file_report(args.file_report)
+    .with_summary_format(args.summary_format)
+    .with_candidate_sort(args.candidate_sort)
+    .with_batch_blame_ranges(args.batch_blame_ranges)
+    .with_parallel_files(args.parallel_files)
+    .with_blame_cache_file(args.blame_cache_file)
+    .with_context_column(args.context_column)
+    .with_max_candidates(args.max_candidates)
+    .with_candidate_date(args.candidate_date)
+    .with_max_candidate_age(args.max_candidate_age)
+    .with_markers(args.ancestor_char, args.unknown_char, args.added_char)
+    .with_record_separator(args.record_separator)
+    .with_json(args.json)
+    .with_first_only(args.first_only)
+    .with_ref_numbers(args.ref_numbers)
+    .with_only_files_changed_by(args.only_files_changed_by)
+    .with_skip_extensions(args.skip_extension)
+    .with_stable_colors(args.stable_colors)
+    .with_link_url(args.link_url.filter(|_| io::stdout().is_terminal()))
+    .with_side(args.side)
+    .with_side_width(args.side_width.or_else(|| {
+        std::env::var("COLUMNS")
+            .ok()
+            .and_then(|columns| columns.parse().ok())
+    }));
+    let mut cand_writer: Box<dyn io::Write> = match &args.candidates_file {
+        Some(path) => Box::new(io::BufWriter::new(std::fs::File::create(path)?)),
+        None => Box::new(io::stderr()),
+    };
+    let result = match args.pager.filter(|_| io::stdout().is_terminal()) {
+        Some(cmd) => {
+            let cmd = cmd
+                .or_else(|| std::env::var("PAGER").ok())
+                .unwrap_or_else(|| "less -R".to_string());
+            let mut pager = Pager::spawn(&cmd)?;
+            let result = annotator.annotate_diff(reader, &mut pager, &mut cand_writer);
+            pager.wait()?;
+            result
+        }
+        None => annotator.annotate_diff(reader, io::stdout(), &mut cand_writer),
+    };
+    let result = result.and_then(|()| cand_writer.flush());
+    match result {
+        // the pager or a downstream command quit early
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
 }
@@ -0,0 +1,80 @@
+use std::io::{self, Write};
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+
+/// Pipe output through a pager.
+///
+/// The `Pager` spawns a pager command through the shell and forwards everything written to it
+/// to the pager's standard input, similar to what git does for its own output.
+pub struct Pager {
+    child: Child,
+    stdin: Option<ChildStdin>,
+}
+
+impl Pager {
+    /// Spawn a new `Pager`.
+    ///
+    /// * `cmd` - The pager command to run, interpreted by `sh`.
+    pub fn spawn(cmd: &str) -> io::Result<Self> {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd).stdin(Stdio::piped());
+        if std::env::var_os("LESS").is_none() {
+            // as git does, quit less if output fits the screen and pass through colors
+            command.env("LESS", "FRX");
+        }
+        let mut child = command
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("Pager cmd: {cmd}")))?;
+        let stdin = child.stdin.take();
+        Ok(Pager { child, stdin })
+    }
+
+    /// Close the pager input and wait for the user to quit it.
+    pub fn wait(mut self) -> io::Result<ExitStatus> {
+        drop(self.stdin.take());
+        self.child.wait()
+    }
+}
+
+impl Write for Pager {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.stdin {
+            Some(stdin) => stdin.write(buf),
+            None => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.stdin {
+            Some(stdin) => stdin.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_pager() {
+        let path =
+            std::env::temp_dir().join(format!("blaming-diff-filter-{}.pager", std::process::id()));
+        let mut pager = Pager::spawn(&format!("cat > '{}'", path.display())).unwrap();
+        writeln!(pager, "b40c1d  foo").unwrap();
+        assert!(pager.wait().unwrap().success());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "b40c1d  foo\n");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_pager_quit() {
+        let mut pager = Pager::spawn("true").unwrap();
+        let err = (0..1024)
+            .map(|_| pager.write_all(&[b'x'; 4096]))
+            .find_map(Result::err)
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert!(pager.wait().unwrap().success());
+    }
+}
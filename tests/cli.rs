@@ -0,0 +1,200 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+const ANNOTATED: &str = "diff --git a/tests/bar.txt b/tests/bar.txt
+--- a/tests/bar.txt
++++ b/tests/bar.txt
+@@ -1,2 +1,2 @@
+b40c1d -bar
+++++++ +barbara
+6ec7db  0.5
+";
+
+fn run(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_blaming-diff-filter"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_input_file() {
+    let output = run(&["--input", "tests/bar.diff"], "");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), ANNOTATED);
+}
+
+#[test]
+fn test_ignore_revs_stdin() {
+    let output = run(
+        &["--input", "tests/bar.diff", "--ignore-revs-stdin"],
+        "# reformatting\n6ec7db\n",
+    );
+    assert!(output.status.success());
+    // the lines of 6ec7db are unblamable without it, and stay with it
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), ANNOTATED);
+    let output = run(
+        &["--input", "tests/bar.diff", "--ignore-revs-stdin"],
+        "does-not-exist\n",
+    );
+    assert!(!output.status.success());
+    // stdin can't be both the diff and the revs
+    let output = run(&["--input", "-", "--ignore-revs-stdin"], "");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_input_stdin() {
+    let diff = std::fs::read_to_string("tests/bar.diff").unwrap();
+    for args in [&[][..], &["--input", "-"]] {
+        let output = run(args, &diff);
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), ANNOTATED);
+    }
+}
+
+#[test]
+fn test_input_missing() {
+    let output = run(&["--input", "tests/missing.diff"], "");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("tests/missing.diff"), "{stderr}");
+}
+
+#[test]
+fn test_candidates_file() {
+    let path = std::env::temp_dir().join(format!(
+        "blaming-diff-filter-{}.candidates",
+        std::process::id()
+    ));
+    let path_arg = path.to_str().unwrap();
+    let args = [
+        "--input",
+        "tests/bar.diff",
+        "--format=%s",
+        "--candidates-file",
+        path_arg,
+    ];
+    let output = run(&args, "");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), ANNOTATED);
+    assert!(output.stderr.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "tests: Add some test data\ntests: Add some changes to test files for blame testing\n"
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_link_url_not_a_terminal() {
+    let args = [
+        "--link-url",
+        "https://example.com/{commit}",
+        "--input",
+        "tests/bar.diff",
+    ];
+    let output = run(&args, "");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), ANNOTATED);
+}
+
+#[test]
+fn test_color() {
+    for (color, escapes) in [("auto", false), ("always", true), ("never", false)] {
+        let color = format!("--color={color}");
+        let args = ["--color-by=commit", "-f", "%C(auto)%h", &color];
+        let output = run(&[&args[..], &["--input", "tests/bar.diff"]].concat(), "");
+        assert!(output.status.success());
+        // output is piped, so auto leaves out colors
+        assert_eq!(output.stdout.contains(&b'\x1b'), escapes, "{color}");
+        assert_eq!(output.stderr.contains(&b'\x1b'), escapes, "{color}");
+    }
+}
+
+#[test]
+fn test_stable_colors() {
+    for color_by in ["--color-by=commit", "--color-commits"] {
+        let args = [color_by, "--stable-colors", "--input", "tests/bar.diff"];
+        assert!(run(&args, "").status.success(), "{color_by}");
+    }
+}
+
+#[test]
+fn test_markers() {
+    let args = ["--added-char=*", "--input", "tests/bar.diff"];
+    let output = run(&args, "");
+    assert!(output.status.success());
+    let annotated = ANNOTATED.replace("++++++ +", "****** +");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), annotated);
+    for marker in ["", "**", "\t", "漢", "\u{301}"] {
+        let output = run(&["--unknown-char", marker], "");
+        assert!(!output.status.success(), "{marker:?}");
+    }
+}
+
+#[test]
+fn test_output_json() {
+    let output = run(&["--output=json", "--input", "tests/bar.diff"], "");
+    assert!(output.status.success());
+    let json = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        json,
+        concat!(
+            r#"{"version":2,"files":[{"path":"tests/bar.txt","lines":["#,
+            r#"{"file":"tests/bar.txt","old_line":null,"kind":"header","commit":null,"text":"diff --git a/tests/bar.txt b/tests/bar.txt"},"#,
+            r#"{"file":"tests/bar.txt","old_line":null,"kind":"header","commit":null,"text":"--- a/tests/bar.txt"},"#,
+            r#"{"file":"tests/bar.txt","old_line":null,"kind":"header","commit":null,"text":"+++ b/tests/bar.txt"},"#,
+            r#"{"file":"tests/bar.txt","old_line":null,"kind":"header","commit":null,"text":"@@ -1,2 +1,2 @@"},"#,
+            r#"{"file":"tests/bar.txt","old_line":1,"kind":"removed","commit":"b40c1d","text":"-bar"},"#,
+            r#"{"file":"tests/bar.txt","old_line":null,"kind":"added","commit":null,"text":"+barbara"},"#,
+            r#"{"file":"tests/bar.txt","old_line":2,"kind":"context","commit":"6ec7db","text":" 0.5"}"#,
+            r#"]}],"candidates":["6ec7db","b40c1d"]}"#,
+            "\n"
+        )
+    );
+    assert_eq!(
+        run(&["--json", "--input", "tests/bar.diff"], "").stdout,
+        json.as_bytes()
+    );
+
+    let output = run(&["--output=json", "--wrap=80"], "");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("--output=json cannot be used with --wrap"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_abbrev() {
+    let output = run(&["--abbrev", "6", "--input", "tests/bar.diff"], "");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), ANNOTATED);
+    let output = run(&["--abbrev", "6", "--min-abbrev", "6"], "");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_show() {
+    let output = run(&["--show=subject=1", "--input", "tests/bar.diff"], "");
+    assert!(output.status.success());
+    for args in [
+        &["--show=subject=0"][..],
+        &["--show=age", "--columns", "hash"],
+    ] {
+        let output = run(args, "");
+        assert!(!output.status.success(), "{args:?}");
+    }
+}